@@ -0,0 +1,249 @@
+//! Нагрузочный стенд рассылки: тысячи SSE‑подписчиков в одной комнате.
+//!
+//! cargo run --release --example fanout_bench -- <файл.room> [подписчиков] [сообщений] [сервер]
+//!
+//! Подключает `подписчиков` (по умолчанию 1000) наблюдателей `bench_<n>` к
+//! запущенному серверу (по умолчанию http://127.0.0.1:7070) и от имени учителя
+//! рассылает `сообщений` (по умолчанию 200) обычных сообщений (без команды,
+//! payload `{"bench": ...}`):
+//!
+//! - всем (`target` нет) — ждём сообщений × подписчиков доставок;
+//! - по одному адресату (`scope: ids`) — время не должно расти с числом подписчиков.
+//!
+//! Каждое подключение — открытый сокет у клиента и у сервера: для больших
+//! чисел поднимите `ulimit -n`. Сообщения стенда попадают в историю комнаты.
+
+use hmac::{Hmac, KeyInit, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const OBSERVER: &str = "наблюдатель";
+const TEACHER: &str = "учитель";
+
+struct Room {
+    room: String,
+    teacher: String,
+    sign_key: String,
+}
+
+impl Room {
+    fn load(path: &str) -> Room {
+        let content = std::fs::read_to_string(path).expect("не удалось прочитать файл комнаты");
+        let get = |key: &str| {
+            content
+                .lines()
+                .filter_map(|l| l.split_once('='))
+                .find(|(k, _)| k.trim() == key)
+                .map(|(_, v)| v.trim().to_string())
+                .unwrap_or_else(|| panic!("в файле комнаты нет `{}`", key))
+        };
+        Room {
+            room: get("room"),
+            teacher: get("teacher"),
+            sign_key: get("sign_key"),
+        }
+    }
+
+    fn sign(&self, id: &str, role: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.sign_key.as_bytes()).unwrap();
+        mac.update(format!("{}:{}:{}", self.room, id, role).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// Счётчики доставок, общие для всех подписчиков
+#[derive(Default)]
+struct Stats {
+    connected: AtomicU64,
+    received: AtomicU64,
+    latency_us: AtomicU64,
+    max_latency_us: AtomicU64,
+}
+
+impl Stats {
+    fn reset(&self) {
+        self.received.store(0, Ordering::SeqCst);
+        self.latency_us.store(0, Ordering::SeqCst);
+        self.max_latency_us.store(0, Ordering::SeqCst);
+    }
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+/// Один SSE‑подписчик: читает поток и учитывает сообщения стенда
+async fn subscribe(client: reqwest::Client, url: String, stats: Arc<Stats>) {
+    let mut resp = match client.get(&url).send().await {
+        Ok(resp) if resp.status().is_success() => resp,
+        Ok(resp) => {
+            eprintln!("подключение отклонено: {}", resp.status());
+            return;
+        }
+        Err(e) => {
+            eprintln!("подключение не удалось: {}", e);
+            return;
+        }
+    };
+    let mut buf = String::new();
+    while let Ok(Some(chunk)) = resp.chunk().await {
+        buf.push_str(&String::from_utf8_lossy(&chunk));
+        while let Some(pos) = buf.find('\n') {
+            let line: String = buf.drain(..=pos).collect();
+            let Some(data) = line.trim_end().strip_prefix("data: ") else {
+                continue;
+            };
+            let Ok(msg) = serde_json::from_str::<Value>(data) else {
+                continue;
+            };
+            match msg["command"].as_str() {
+                Some("WELCOME") => {
+                    stats.connected.fetch_add(1, Ordering::SeqCst);
+                }
+                None if msg["payload"]["bench"].is_object() => {
+                    let sent = msg["payload"]["bench"]["t"].as_u64().unwrap_or(0);
+                    let latency = now_us().saturating_sub(sent);
+                    stats.latency_us.fetch_add(latency, Ordering::SeqCst);
+                    stats.max_latency_us.fetch_max(latency, Ordering::SeqCst);
+                    stats.received.fetch_add(1, Ordering::SeqCst);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+struct Bench {
+    client: reqwest::Client,
+    server: String,
+    room: Room,
+    stats: Arc<Stats>,
+    messages: usize,
+}
+
+impl Bench {
+    /// Рассылает `messages` сообщений стенда и ждёт `expected` доставок
+    async fn run_phase(&self, name: &str, expected: u64, target: impl Fn(usize) -> Option<Value>) {
+        let Bench {
+            client,
+            server,
+            room,
+            stats,
+            messages,
+        } = self;
+        let messages = *messages;
+        stats.reset();
+        let sign = room.sign(&room.teacher, TEACHER);
+        let started = Instant::now();
+        for n in 0..messages {
+            let mut body = json!({
+                "room_id": room.room,
+                "sender": { "id": room.teacher, "type": TEACHER, "sign": sign },
+                "payload": { "bench": { "t": now_us(), "n": n } },
+            });
+            if let Some(target) = target(n) {
+                body["target"] = target;
+            }
+            if let Err(e) = client
+                .post(format!("{}/send", server))
+                .json(&body)
+                .send()
+                .await
+            {
+                eprintln!("/send: {}", e);
+            }
+        }
+        let sent = started.elapsed();
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while stats.received.load(Ordering::SeqCst) < expected && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let elapsed = started.elapsed();
+        let received = stats.received.load(Ordering::SeqCst);
+        let avg = stats.latency_us.load(Ordering::SeqCst) / received.max(1);
+        println!(
+            "{:<5} отправлено {} за {:?}, доставлено {}/{} за {:?} ({:.0} доставок/с)",
+            name,
+            messages,
+            sent,
+            received,
+            expected,
+            elapsed,
+            received as f64 / elapsed.as_secs_f64(),
+        );
+        println!(
+            "      задержка ср. {:.2} мс, макс. {:.2} мс",
+            avg as f64 / 1000.0,
+            stats.max_latency_us.load(Ordering::SeqCst) as f64 / 1000.0,
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let room = Room::load(
+        &args
+            .next()
+            .expect("использование: fanout_bench <файл.room> [подписчиков] [сообщений] [сервер]"),
+    );
+    let subscribers: usize = args.next().and_then(|n| n.parse().ok()).unwrap_or(1000);
+    let messages: usize = args.next().and_then(|n| n.parse().ok()).unwrap_or(200);
+    let server = args
+        .next()
+        .unwrap_or_else(|| "http://127.0.0.1:7070".to_string());
+
+    let client = reqwest::Client::new();
+    let stats = Arc::new(Stats::default());
+
+    println!(
+        "Подключаем {} SSE‑подписчиков к {}...",
+        subscribers, room.room
+    );
+    let started = Instant::now();
+    for n in 0..subscribers {
+        let id = format!("bench_{}", n);
+        let query: String = form_urlencoded::Serializer::new(String::new())
+            .append_pair("room", &room.room)
+            .append_pair("id", &id)
+            .append_pair("type", OBSERVER)
+            .append_pair("sign", &room.sign(&id, OBSERVER))
+            .finish();
+        tokio::spawn(subscribe(
+            client.clone(),
+            format!("{}/sse?{}", server, query),
+            stats.clone(),
+        ));
+    }
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while (stats.connected.load(Ordering::SeqCst) as usize) < subscribers
+        && Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let connected = stats.connected.load(Ordering::SeqCst);
+    println!("Подключено {} за {:?}", connected, started.elapsed());
+
+    let bench = Bench {
+        client,
+        server,
+        room,
+        stats,
+        messages,
+    };
+    bench
+        .run_phase("all", (messages as u64) * connected, |_| None)
+        .await;
+    bench
+        .run_phase("ids", messages as u64, |n| {
+            Some(json!({ "scope": "ids", "ids": [format!("bench_{}", n % subscribers)] }))
+        })
+        .await;
+}
@@ -0,0 +1,69 @@
+use super::{issue_token, unknown_room, verify};
+use crate::errors::{ApiError, ErrorCode};
+use crate::AppState;
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+
+/// Вход в комнату: тот же id, роль и подпись, что и в query подключения
+#[derive(Deserialize)]
+struct LoginRequest {
+    room_id: String,
+    id: String,
+    #[serde(rename = "type")]
+    role: String,
+    sign: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String,
+    room_id: String,
+    id: String,
+    #[serde(rename = "type")]
+    role: String,
+    /// Когда токен перестанет действовать, unix‑время в секундах
+    expires_at: u64,
+    /// Через сколько секунд, для удобства клиента
+    expires_in: u64,
+}
+
+/// POST /login: проверяет подпись пользователя и его допуск в комнату
+/// (teacher, authorised_students, не удалён ли он учителем) и выдаёт короткоживущий токен доступа.
+/// Токен принимают /ws, /sse, /lp и /send — как `?token=` или `Authorization: Bearer`.
+pub async fn login(state: web::Data<AppState>, body: web::Bytes) -> impl Responder {
+    let req: LoginRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(e) => {
+            return ApiError::new(ErrorCode::MalformedMessage, e.to_string()).error_response()
+        }
+    };
+    let Some(room) = state.rooms.get(&req.room_id).await else {
+        return unknown_room(&req.room_id).error_response();
+    };
+    let config = room.config.read().await;
+    if let Err(e) = verify(
+        &config,
+        &req.room_id,
+        &req.id,
+        &req.role,
+        req.sign.as_deref(),
+    ) {
+        return e.error_response();
+    }
+    if let Err(e) = room.control.lock().await.admit(&req.id) {
+        return e.error_response();
+    }
+    let (token, claims) = issue_token(&config, &req.id, &req.role);
+    println!(
+        "Комната {}: выдан токен {} ({})",
+        claims.room, claims.id, claims.role
+    );
+    HttpResponse::Ok().json(LoginResponse {
+        token,
+        expires_in: config.token_ttl.as_secs().max(1),
+        room_id: claims.room,
+        id: claims.id,
+        role: claims.role,
+        expires_at: claims.exp,
+    })
+}
@@ -0,0 +1,483 @@
+pub mod login;
+
+use crate::config::RoomConfig;
+use crate::errors::{ApiError, ErrorCode};
+use crate::rooms::{Room, RoomRegistry};
+use crate::validator::message::{IncomingMessage, Sender};
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Строка, которую подписывает клиент: комната, id и роль.
+/// Подпись выдаётся пользователю заранее (тем, кто знает sign_key комнаты)
+/// и подтверждает, что он действительно тот, за кого себя выдаёт.
+fn signed_data(room: &str, id: &str, role: &str) -> String {
+    format!("{}:{}:{}", room, id, role)
+}
+
+fn mac(key: &str, data: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC принимает ключ любой длины");
+    mac.update(data.as_bytes());
+    mac
+}
+
+/// HMAC-SHA256 подпись в hex. Сервер подписи не выдаёт (см. README) —
+/// функция нужна тестам.
+#[cfg(test)]
+pub fn sign(key: &str, room: &str, id: &str, role: &str) -> String {
+    hex::encode(
+        mac(key, &signed_data(room, id, role))
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// Строка токена продолжения сессии: к подписи пользователя добавляется сессия
+fn resume_data(room: &str, id: &str, role: &str, session: &str) -> String {
+    format!("resume:{}:{}", signed_data(room, id, role), session)
+}
+
+/// Токен продолжения сессии `<session_id>.<hex hmac>`: выдаётся в WELCOME
+/// и позволяет переподключившемуся клиенту занять свою прежнюю сессию.
+pub fn resume_token(key: &str, room: &str, id: &str, role: &str, session: &str) -> String {
+    let mac = mac(key, &resume_data(room, id, role, session));
+    format!("{}.{}", session, hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Проверяет токен продолжения сессии этого пользователя, возвращает id сессии.
+pub fn verify_resume(
+    config: &RoomConfig,
+    room: &str,
+    id: &str,
+    role: &str,
+    token: &str,
+) -> Result<String, ApiError> {
+    let invalid = || ApiError::new(ErrorCode::InvalidResumeToken, "Неверный токен сессии");
+    let (session, signature) = token.split_once('.').ok_or_else(invalid)?;
+    let bytes = hex::decode(signature).map_err(|_| invalid())?;
+    mac(&config.sign_key, &resume_data(room, id, role, session))
+        .verify_slice(&bytes)
+        .map_err(|_| invalid())?;
+    Ok(session.to_string())
+}
+
+/// Кого удостоверяет токен доступа
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TokenClaims {
+    pub room: String,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub role: String,
+    /// До какого момента токен действует, unix‑время в секундах
+    pub exp: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Строка, которую подписывает сервер в токене доступа
+fn token_data(claims: &str) -> String {
+    format!("token:{}", claims)
+}
+
+/// Токен доступа `<hex claims>.<hex hmac>`, подписанный sign_key комнаты.
+/// Выдаётся /login тому, кто уже прошёл проверку подписи.
+pub fn issue_token(config: &RoomConfig, id: &str, role: &str) -> (String, TokenClaims) {
+    let claims = TokenClaims {
+        room: config.room.clone(),
+        id: id.to_string(),
+        role: role.to_string(),
+        exp: now_secs() + config.token_ttl.as_secs().max(1),
+    };
+    let encoded = hex::encode(serde_json::to_vec(&claims).unwrap_or_default());
+    let mac = mac(&config.sign_key, &token_data(&encoded));
+    let token = format!("{}.{}", encoded, hex::encode(mac.finalize().into_bytes()));
+    (token, claims)
+}
+
+fn invalid_token(message: &str) -> ApiError {
+    ApiError::new(ErrorCode::InvalidToken, message).field("token")
+}
+
+/// Содержимое токена без проверки подписи: по нему находится комната,
+/// ключом которой токен проверяется.
+fn token_claims(token: &str) -> Result<(TokenClaims, &str, &str), ApiError> {
+    let (encoded, signature) = token
+        .split_once('.')
+        .ok_or_else(|| invalid_token("Неверный токен доступа"))?;
+    let claims = hex::decode(encoded)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(|| invalid_token("Неверный токен доступа"))?;
+    Ok((claims, encoded, signature))
+}
+
+/// Проверяет токен доступа по конфигу его комнаты. Пользователь должен
+/// по‑прежнему быть допущен в комнату с этой ролью.
+pub fn verify_token(config: &RoomConfig, token: &str) -> Result<TokenClaims, ApiError> {
+    let (claims, encoded, signature) = token_claims(token)?;
+    let bytes = hex::decode(signature).map_err(|_| invalid_token("Неверный токен доступа"))?;
+    if claims.room != config.room {
+        return Err(invalid_token("Токен выдан для другой комнаты"));
+    }
+    mac(&config.sign_key, &token_data(encoded))
+        .verify_slice(&bytes)
+        .map_err(|_| invalid_token("Неверная подпись токена"))?;
+    if claims.exp <= now_secs() {
+        return Err(
+            ApiError::new(ErrorCode::TokenExpired, "Срок действия токена истёк").field("token"),
+        );
+    }
+    if !config.role_allowed(&claims.id, &claims.role) {
+        return Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            format!(
+                "Пользователь {} не может войти в комнату с ролью {}",
+                claims.id, claims.role
+            ),
+        )
+        .field("token"));
+    }
+    Ok(claims)
+}
+
+/// Находит комнату токена и проверяет его её ключом
+pub async fn verify_token_in(
+    rooms: &RoomRegistry,
+    token: &str,
+) -> Result<(Arc<Room>, TokenClaims), ApiError> {
+    let (claims, _, _) = token_claims(token)?;
+    let room = rooms
+        .get(&claims.room)
+        .await
+        .ok_or_else(|| unknown_room(&claims.room))?;
+    let claims = verify_token(&*room.config.read().await, token)?;
+    Ok((room, claims))
+}
+
+/// Токен доступа запроса: `Authorization: Bearer <token>` или `?token=`
+pub fn request_token(req: &HttpRequest) -> Option<String> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string());
+    bearer
+        .or_else(|| {
+            form_urlencoded::parse(req.query_string().as_bytes())
+                .find(|(k, _)| k == "token")
+                .map(|(_, v)| v.into_owned())
+        })
+        .filter(|t| !t.is_empty())
+}
+
+/// Кто открывает подключение (/ws, /sse): по токену доступа, а без него —
+/// по room, id, type и sign из query. Возвращает комнату, id и роль.
+/// Удалённого из комнаты (KICK) не пускает.
+pub async fn identify(
+    req: &HttpRequest,
+    query: &HashMap<String, String>,
+    rooms: &RoomRegistry,
+) -> Result<(Arc<Room>, String, String), ApiError> {
+    if let Some(token) = request_token(req) {
+        let (room, claims) = verify_token_in(rooms, &token).await?;
+        if query.get("room").is_some_and(|r| *r != claims.room) {
+            return Err(invalid_token("Токен выдан для другой комнаты"));
+        }
+        room.control.lock().await.admit(&claims.id)?;
+        return Ok((room, claims.id, claims.role));
+    }
+
+    let room_id = query.get("room").cloned().unwrap_or_default();
+    let id = query.get("id").cloned().unwrap_or_default();
+    let role = query.get("type").cloned().unwrap_or_default();
+    let room = rooms
+        .get(&room_id)
+        .await
+        .ok_or_else(|| unknown_room(&room_id))?;
+    verify(
+        &*room.config.read().await,
+        &room_id,
+        &id,
+        &role,
+        query.get("sign").map(String::as_str),
+    )?;
+    room.control.lock().await.admit(&id)?;
+    Ok((room, id, role))
+}
+
+/// Проверяет подпись и право пользователя войти в комнату с этой ролью.
+pub fn verify(
+    config: &RoomConfig,
+    room: &str,
+    id: &str,
+    role: &str,
+    signature: Option<&str>,
+) -> Result<(), ApiError> {
+    let unauthorized =
+        |field: &str, message: String| ApiError::new(ErrorCode::Unauthorized, message).field(field);
+    if room != config.room {
+        return Err(unknown_room(room));
+    }
+    if id.is_empty() {
+        return Err(unauthorized(
+            "sender.id",
+            "Не указан id пользователя".to_string(),
+        ));
+    }
+    let signature = signature
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| unauthorized("sender.sign", "Сообщение не подписано".to_string()))?;
+    let bytes = hex::decode(signature)
+        .map_err(|_| unauthorized("sender.sign", "Подпись должна быть в hex".to_string()))?;
+    mac(&config.sign_key, &signed_data(room, id, role))
+        .verify_slice(&bytes)
+        .map_err(|_| unauthorized("sender.sign", "Неверная подпись".to_string()))?;
+
+    if !config.role_allowed(id, role) {
+        return Err(unauthorized(
+            "sender.type",
+            format!(
+                "Пользователь {} не может войти в комнату с ролью {}",
+                id, role
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Ошибка «неизвестная комната»
+pub fn unknown_room(room: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::InvalidRoom,
+        format!("Неизвестная комната: {}", room),
+    )
+    .field("room_id")
+}
+
+/// Проверка подлинности отправителя сообщения
+pub trait Authenticate {
+    /// Комната, по конфигу которой проверяется отправитель
+    fn room_id(&self) -> &str;
+    /// id отправителя: удалённого из комнаты не пускают
+    fn sender_id(&self) -> &str;
+    fn authenticate(&self, config: &RoomConfig) -> Result<(), ApiError>;
+    /// Комната и отправитель из проверенного токена вместо указанных в сообщении
+    fn use_token(&mut self, claims: TokenClaims) -> Result<(), ApiError>;
+}
+
+impl Authenticate for IncomingMessage {
+    fn room_id(&self) -> &str {
+        &self.room_id
+    }
+
+    fn sender_id(&self) -> &str {
+        &self.sender.id
+    }
+
+    fn authenticate(&self, config: &RoomConfig) -> Result<(), ApiError> {
+        verify(
+            config,
+            &self.room_id,
+            &self.sender.id,
+            &self.sender.sender_type,
+            self.sender.sign.as_deref(),
+        )
+    }
+
+    fn use_token(&mut self, claims: TokenClaims) -> Result<(), ApiError> {
+        let mismatch = |field: &str| {
+            ApiError::new(
+                ErrorCode::SenderMismatch,
+                "Сообщение не совпадает с токеном доступа",
+            )
+            .field(field)
+        };
+        // в сообщении их можно не указывать, но указанные должны совпадать
+        if !self.room_id.is_empty() && self.room_id != claims.room {
+            return Err(mismatch("room_id"));
+        }
+        if !self.sender.id.is_empty()
+            && (self.sender.id != claims.id || self.sender.sender_type != claims.role)
+        {
+            return Err(mismatch("sender"));
+        }
+        self.room_id = claims.room;
+        self.sender = Sender {
+            id: claims.id,
+            sender_type: claims.role,
+            sign: None,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RoomConfig {
+        RoomConfig::parse(
+            "room = room_1\n\
+             teacher = user_1\n\
+             authorised_students = user_2, user_3\n\
+             sign_key = secret\n",
+        )
+        .unwrap()
+    }
+
+    /// Токен с произвольными claims, подписанный как настоящий
+    fn signed_token(key: &str, claims: &TokenClaims) -> String {
+        let encoded = hex::encode(serde_json::to_vec(claims).unwrap());
+        let mac = mac(key, &token_data(&encoded));
+        format!("{}.{}", encoded, hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn code<T>(result: Result<T, ApiError>) -> ErrorCode {
+        result.err().expect("ожидалась ошибка").code
+    }
+
+    #[test]
+    fn signature_is_verified() {
+        let config = config();
+        let sign = sign("secret", "room_1", "user_2", "ученик");
+        assert!(verify(&config, "room_1", "user_2", "ученик", Some(&sign)).is_ok());
+        let err = verify(&config, "room_1", "user_3", "ученик", Some(&sign)).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Unauthorized);
+    }
+
+    #[test]
+    fn room_config_decides_who_enters_with_which_role() {
+        let config = config();
+        let check = |id: &str, role: &str| {
+            let signature = sign("secret", "room_1", id, role);
+            verify(&config, "room_1", id, role, Some(&signature))
+        };
+        assert!(check("user_1", "учитель").is_ok());
+        assert!(check("user_2", "ученик").is_ok());
+        assert!(check("user_9", "наблюдатель").is_ok());
+        assert!(check("user_9", "ADMIN").is_ok());
+        assert_eq!(code(check("user_2", "учитель")), ErrorCode::Unauthorized);
+        assert_eq!(code(check("user_9", "ученик")), ErrorCode::Unauthorized);
+        assert_eq!(code(check("user_1", "директор")), ErrorCode::Unauthorized);
+
+        let unsigned = verify(&config, "room_1", "user_2", "ученик", None);
+        assert_eq!(code(unsigned), ErrorCode::Unauthorized);
+        let signature = sign("secret", "room_2", "user_2", "ученик");
+        let other_room = verify(&config, "room_2", "user_2", "ученик", Some(&signature));
+        assert_eq!(code(other_room), ErrorCode::InvalidRoom);
+        let signature = sign("other", "room_1", "user_2", "ученик");
+        let forged = verify(&config, "room_1", "user_2", "ученик", Some(&signature));
+        assert_eq!(code(forged), ErrorCode::Unauthorized);
+    }
+
+    #[test]
+    fn issued_token_is_verified() {
+        let config = config();
+        let (token, _) = issue_token(&config, "user_2", "ученик");
+        let claims = verify_token(&config, &token).unwrap();
+        assert_eq!(claims.room, "room_1");
+        assert_eq!(claims.id, "user_2");
+        assert_eq!(claims.role, "ученик");
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let config = config();
+        let (token, mut claims) = issue_token(&config, "user_2", "ученик");
+        let (_, signature) = token.split_once('.').unwrap();
+
+        // чужие claims под подписью настоящего токена
+        claims.id = "user_1".to_string();
+        claims.role = "учитель".to_string();
+        let encoded = hex::encode(serde_json::to_vec(&claims).unwrap());
+        let forged = format!("{}.{}", encoded, signature);
+        assert_eq!(
+            code(verify_token(&config, &forged)),
+            ErrorCode::InvalidToken
+        );
+
+        let mut broken = token.clone();
+        let last = if broken.ends_with('0') { "1" } else { "0" };
+        broken.replace_range(broken.len() - 1.., last);
+        assert_eq!(
+            code(verify_token(&config, &broken)),
+            ErrorCode::InvalidToken
+        );
+        assert_eq!(
+            code(verify_token(&config, "not-a-token")),
+            ErrorCode::InvalidToken
+        );
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let config = config();
+        let claims = TokenClaims {
+            room: "room_1".to_string(),
+            id: "user_2".to_string(),
+            role: "ученик".to_string(),
+            exp: now_secs() - 1,
+        };
+        let token = signed_token("secret", &claims);
+        assert_eq!(code(verify_token(&config, &token)), ErrorCode::TokenExpired);
+    }
+
+    #[test]
+    fn token_is_bound_to_its_room_and_role() {
+        let config = config();
+        let mut other = config.clone();
+        other.room = "room_2".to_string();
+        let (token, _) = issue_token(&other, "user_2", "ученик");
+        assert_eq!(code(verify_token(&config, &token)), ErrorCode::InvalidToken);
+
+        // роль, в которой пользователь не допущен, токен не даёт
+        let claims = TokenClaims {
+            room: "room_1".to_string(),
+            id: "user_2".to_string(),
+            role: "учитель".to_string(),
+            exp: now_secs() + 60,
+        };
+        let token = signed_token("secret", &claims);
+        assert_eq!(code(verify_token(&config, &token)), ErrorCode::Unauthorized);
+
+        // пользователя убрали из конфига — его токен больше не действует
+        let (token, _) = issue_token(&config, "user_3", "ученик");
+        let mut reloaded = config.clone();
+        reloaded.authorised_students.retain(|id| id != "user_3");
+        assert_eq!(
+            code(verify_token(&reloaded, &token)),
+            ErrorCode::Unauthorized
+        );
+    }
+
+    #[test]
+    fn resume_token_belongs_to_its_session_and_user() {
+        let config = config();
+        let token = resume_token("secret", "room_1", "user_2", "ученик", "s1");
+        let session = verify_resume(&config, "room_1", "user_2", "ученик", &token).unwrap();
+        assert_eq!(session, "s1");
+
+        let stolen = verify_resume(&config, "room_1", "user_3", "ученик", &token);
+        assert_eq!(code(stolen), ErrorCode::InvalidResumeToken);
+        let promoted = verify_resume(&config, "room_1", "user_2", "учитель", &token);
+        assert_eq!(code(promoted), ErrorCode::InvalidResumeToken);
+        let (_, signature) = token.split_once('.').unwrap();
+        let other_session = format!("s2.{}", signature);
+        let hijacked = verify_resume(&config, "room_1", "user_2", "ученик", &other_session);
+        assert_eq!(code(hijacked), ErrorCode::InvalidResumeToken);
+    }
+}
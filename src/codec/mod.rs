@@ -0,0 +1,153 @@
+use crate::errors::{ApiError, ErrorCode};
+use crate::validator::message::IncomingMessage;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+/// Подпротоколы WebSocket, которые сервер согласует через Sec-WebSocket-Protocol
+pub const PROTOCOLS: &[&str] = &["json", "msgpack", "cbor"];
+
+/// Кодировка сообщений WS‑подключения. Текстовые кадры всегда JSON;
+/// двоичные кадры — в согласованной кодировке. Кодировка своя у каждого
+/// подключения, поэтому JSON‑ и двоичные клиенты делят одну комнату.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+/// Закодированное сообщение: текстовый или двоичный кадр
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Encoding {
+    pub fn parse(val: &str) -> Result<Self, ApiError> {
+        match val {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MsgPack),
+            "cbor" => Ok(Encoding::Cbor),
+            other => Err(ApiError::new(
+                ErrorCode::UnsupportedEncoding,
+                format!("Неизвестная кодировка `{}` (json | msgpack | cbor)", other),
+            )
+            .field("encoding")),
+        }
+    }
+
+    /// Кодировка подключения: `?encoding=` или подпротокол из
+    /// Sec-WebSocket-Protocol (первый известный — его же выберет рукопожатие)
+    pub fn from_request(req: &HttpRequest, query: Option<&str>) -> Result<Self, ApiError> {
+        if let Some(val) = query {
+            return Encoding::parse(val);
+        }
+        let protocol = req
+            .headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').map(str::trim).find(|p| PROTOCOLS.contains(p)));
+        match protocol {
+            Some(p) => Encoding::parse(p),
+            None => Ok(Encoding::Json),
+        }
+    }
+
+    /// Разбирает двоичный кадр
+    pub fn decode(&self, data: &[u8]) -> Result<IncomingMessage, ApiError> {
+        let malformed = |e: String| ApiError::new(ErrorCode::MalformedMessage, e);
+        match self {
+            Encoding::Json => Err(malformed(
+                "Двоичные кадры не согласованы (encoding=json)".to_string(),
+            )),
+            Encoding::MsgPack => rmp_serde::from_slice(data).map_err(|e| malformed(e.to_string())),
+            Encoding::Cbor => ciborium::from_reader(data).map_err(|e| malformed(e.to_string())),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Frame, String> {
+        match self {
+            Encoding::Json => serde_json::to_string(value)
+                .map(Frame::Text)
+                .map_err(|e| e.to_string()),
+            // поля — по именам, как в JSON
+            Encoding::MsgPack => rmp_serde::to_vec_named(value)
+                .map(Frame::Binary)
+                .map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf).map_err(|e| e.to_string())?;
+                Ok(Frame::Binary(buf))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::{json, Value};
+
+    fn message() -> Value {
+        json!({
+            "room_id": "room_1",
+            "sender": {"id": "user_1", "type": "учитель"},
+            "target": {"scope": "ids", "ids": ["user_2"], "types": []},
+            "command": "MOVE_CUBE",
+            "payload": {"id": "c1", "position": {"x": 1.5, "y": 0.0, "z": -2.0}},
+            "msg_id": "m1",
+        })
+    }
+
+    #[test]
+    fn messages_survive_a_round_trip_in_every_encoding() {
+        let original: IncomingMessage = serde_json::from_value(message()).unwrap();
+        for encoding in [Encoding::Json, Encoding::MsgPack, Encoding::Cbor] {
+            let decoded = match encoding.encode(&original).unwrap() {
+                Frame::Text(text) => {
+                    assert_eq!(encoding, Encoding::Json);
+                    serde_json::from_str(&text).unwrap()
+                }
+                Frame::Binary(data) => encoding.decode(&data).unwrap(),
+            };
+            let decoded = serde_json::to_value(decoded).unwrap();
+            assert_eq!(
+                decoded,
+                serde_json::to_value(&original).unwrap(),
+                "{encoding:?}"
+            );
+        }
+        let err = Encoding::Json.decode(b"{}").unwrap_err();
+        assert_eq!(err.code, ErrorCode::MalformedMessage);
+        let err = Encoding::MsgPack.decode(b"\xc1").unwrap_err();
+        assert_eq!(err.code, ErrorCode::MalformedMessage);
+    }
+
+    #[test]
+    fn encoding_is_negotiated_from_query_or_subprotocol() {
+        let negotiate = |protocol: Option<&str>, query: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(protocol) = protocol {
+                req = req.insert_header((header::SEC_WEBSOCKET_PROTOCOL, protocol));
+            }
+            Encoding::from_request(&req.to_http_request(), query).map_err(|e| e.code)
+        };
+        assert_eq!(negotiate(None, None), Ok(Encoding::Json));
+        assert_eq!(negotiate(Some("cbor"), None), Ok(Encoding::Cbor));
+        // первый известный подпротокол
+        assert_eq!(
+            negotiate(Some("chat, msgpack, cbor"), None),
+            Ok(Encoding::MsgPack)
+        );
+        assert_eq!(negotiate(Some("chat"), None), Ok(Encoding::Json));
+        // ?encoding= важнее заголовка
+        assert_eq!(negotiate(Some("cbor"), Some("json")), Ok(Encoding::Json));
+        assert_eq!(
+            negotiate(None, Some("xml")),
+            Err(ErrorCode::UnsupportedEncoding)
+        );
+    }
+}
@@ -0,0 +1,531 @@
+use crate::config::RoomConfig;
+use crate::control::{Control, ControlCommand};
+use crate::errors::{ApiError, ErrorCode};
+use crate::permissions;
+use crate::scene::{Cube, Position, SceneCommand};
+use crate::validator::message::IncomingMessage;
+use schemars::{schema_for, JsonSchema, Schema};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Все роли клиентов
+const EVERYONE: &[&str] = &["учитель", "ADMIN", "ученик", "наблюдатель"];
+/// Роли, которые могут менять сцену
+const EDITORS: &[&str] = &["учитель", "ADMIN"];
+/// Роли, которые управляют комнатой; передача управления ученику их не даёт
+const MODERATORS: &[&str] = &["учитель", "ADMIN"];
+/// Роли, которые участвуют в занятии (наблюдатель только смотрит)
+const PARTICIPANTS: &[&str] = &["учитель", "ADMIN", "ученик"];
+
+/// Описание команды в реестре
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Роли, которым команда разрешена
+    pub roles: &'static [&'static str],
+    /// Меняет ли команда состояние комнаты; команда, которая его только читает,
+    /// получает ответ сама и не рассылается
+    pub mutates: bool,
+    /// Адресована серверу: target для неё не важен
+    pub to_server: bool,
+    /// JSON Schema payload; `None` — payload не нужен
+    pub payload: fn() -> Option<Schema>,
+    /// Разбор payload в типизированную команду
+    parse: fn(&str, Option<&Value>) -> Result<Command, ApiError>,
+}
+
+/// Типизированная команда клиента. Вариант определяет обработчик:
+/// сцена комнаты, само WS‑подключение или рассылка как есть.
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Читает или меняет сцену комнаты
+    Scene(SceneCommand),
+    /// Управление комнатой: KICK, MUTE, блокировка сцены, передача управления
+    Control(ControlCommand),
+    /// Продолжение сессии WS‑подключения
+    Hello(HelloPayload),
+    /// Повторная доставка пропущенного WS‑подключению
+    Resume(ResumePayload),
+    /// Команды нет в реестре: допустима, только если комната пересылает её в upstream
+    Upstream(String),
+    /// Сообщение без команды
+    Plain,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct MovePayload {
+    id: String,
+    position: Position,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct SelectPayload {
+    id: String,
+    #[serde(default = "default_selected")]
+    selected: bool,
+}
+
+fn default_selected() -> bool {
+    true
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct IdPayload {
+    id: String,
+}
+
+/// PASS_CONTROL {id?}: без id управление возвращается учителю
+#[derive(Default, Deserialize, JsonSchema)]
+struct PassControlPayload {
+    id: Option<String>,
+}
+
+/// HELLO {resume_token?, since?}
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct HelloPayload {
+    pub resume_token: Option<String>,
+    pub since: Option<u64>,
+}
+
+/// RESUME {since}
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct ResumePayload {
+    #[serde(default)]
+    pub since: u64,
+}
+
+/// Реестр команд, которые клиент может отправить серверу
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "GET_STATE",
+        roles: EVERYONE,
+        mutates: false,
+        to_server: true,
+        payload: || None,
+        parse: |_, _| Ok(Command::Scene(SceneCommand::Get)),
+    },
+    CommandSpec {
+        name: "ADD_CUBE",
+        roles: EDITORS,
+        mutates: true,
+        to_server: false,
+        payload: || Some(schema_for!(Cube)),
+        parse: |name, payload| {
+            required::<Cube>(name, payload).map(|cube| Command::Scene(SceneCommand::Add(cube)))
+        },
+    },
+    CommandSpec {
+        name: "MOVE_CUBE",
+        roles: EDITORS,
+        mutates: true,
+        to_server: false,
+        payload: || Some(schema_for!(MovePayload)),
+        parse: |name, payload| {
+            required::<MovePayload>(name, payload).map(|p| {
+                Command::Scene(SceneCommand::Move {
+                    id: p.id,
+                    position: p.position,
+                })
+            })
+        },
+    },
+    CommandSpec {
+        name: "SELECT_CUBE",
+        roles: PARTICIPANTS,
+        mutates: true,
+        to_server: false,
+        payload: || Some(schema_for!(SelectPayload)),
+        parse: |name, payload| {
+            required::<SelectPayload>(name, payload).map(|p| {
+                Command::Scene(SceneCommand::Select {
+                    id: p.id,
+                    selected: p.selected,
+                })
+            })
+        },
+    },
+    CommandSpec {
+        name: "REMOVE_CUBE",
+        roles: EDITORS,
+        mutates: true,
+        to_server: false,
+        payload: || Some(schema_for!(IdPayload)),
+        parse: |name, payload| {
+            required::<IdPayload>(name, payload)
+                .map(|p| Command::Scene(SceneCommand::Remove { id: p.id }))
+        },
+    },
+    CommandSpec {
+        name: "RESET_STATE",
+        roles: EDITORS,
+        mutates: true,
+        to_server: false,
+        payload: || None,
+        parse: |_, _| Ok(Command::Scene(SceneCommand::Reset)),
+    },
+    // управление комнатой: меняет состояние, рассылается событием ROOM_CONTROL
+    CommandSpec {
+        name: "KICK",
+        roles: MODERATORS,
+        mutates: true,
+        to_server: true,
+        payload: || Some(schema_for!(IdPayload)),
+        parse: |name, payload| {
+            required::<IdPayload>(name, payload)
+                .map(|p| Command::Control(ControlCommand::Kick { id: p.id }))
+        },
+    },
+    CommandSpec {
+        name: "UNKICK",
+        roles: MODERATORS,
+        mutates: true,
+        to_server: true,
+        payload: || Some(schema_for!(IdPayload)),
+        parse: |name, payload| {
+            required::<IdPayload>(name, payload)
+                .map(|p| Command::Control(ControlCommand::Unkick { id: p.id }))
+        },
+    },
+    CommandSpec {
+        name: "MUTE",
+        roles: MODERATORS,
+        mutates: true,
+        to_server: true,
+        payload: || Some(schema_for!(IdPayload)),
+        parse: |name, payload| {
+            required::<IdPayload>(name, payload)
+                .map(|p| Command::Control(ControlCommand::Mute { id: p.id }))
+        },
+    },
+    CommandSpec {
+        name: "UNMUTE",
+        roles: MODERATORS,
+        mutates: true,
+        to_server: true,
+        payload: || Some(schema_for!(IdPayload)),
+        parse: |name, payload| {
+            required::<IdPayload>(name, payload)
+                .map(|p| Command::Control(ControlCommand::Unmute { id: p.id }))
+        },
+    },
+    CommandSpec {
+        name: "LOCK_SCENE",
+        roles: MODERATORS,
+        mutates: true,
+        to_server: true,
+        payload: || None,
+        parse: |_, _| Ok(Command::Control(ControlCommand::Lock)),
+    },
+    CommandSpec {
+        name: "UNLOCK_SCENE",
+        roles: MODERATORS,
+        mutates: true,
+        to_server: true,
+        payload: || None,
+        parse: |_, _| Ok(Command::Control(ControlCommand::Unlock)),
+    },
+    CommandSpec {
+        name: "PASS_CONTROL",
+        roles: MODERATORS,
+        mutates: true,
+        to_server: true,
+        payload: || Some(schema_for!(PassControlPayload)),
+        parse: |name, payload| {
+            optional::<PassControlPayload>(name, payload)
+                .map(|p| Command::Control(ControlCommand::PassControl { id: p.id }))
+        },
+    },
+    // только по WS: исполняет само подключение
+    CommandSpec {
+        name: "HELLO",
+        roles: EVERYONE,
+        mutates: false,
+        to_server: true,
+        payload: || Some(schema_for!(HelloPayload)),
+        parse: |name, payload| optional(name, payload).map(Command::Hello),
+    },
+    CommandSpec {
+        name: "RESUME",
+        roles: EVERYONE,
+        mutates: false,
+        to_server: true,
+        payload: || Some(schema_for!(ResumePayload)),
+        parse: |name, payload| optional(name, payload).map(Command::Resume),
+    },
+];
+
+fn invalid_payload(message: String) -> ApiError {
+    ApiError::new(ErrorCode::InvalidPayload, message).field("payload")
+}
+
+/// Обязательный payload команды
+fn required<T: serde::de::DeserializeOwned>(
+    name: &str,
+    payload: Option<&Value>,
+) -> Result<T, ApiError> {
+    let payload =
+        payload.ok_or_else(|| invalid_payload(format!("Команда {} требует payload", name)))?;
+    serde_json::from_value(payload.clone())
+        .map_err(|e| invalid_payload(format!("Неверный payload для {}: {}", name, e)))
+}
+
+/// Необязательный payload: без него — значения по умолчанию
+fn optional<T: serde::de::DeserializeOwned + Default>(
+    name: &str,
+    payload: Option<&Value>,
+) -> Result<T, ApiError> {
+    match payload {
+        None | Some(Value::Null) => Ok(T::default()),
+        Some(_) => required(name, payload),
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// Разбирает сообщение в типизированную команду по реестру.
+/// Некорректный payload — `INVALID_PAYLOAD`; команда не из реестра
+/// разбирается как `Upstream`, её допустимость решает `authorize`.
+pub fn parse(msg: &IncomingMessage) -> Result<Command, ApiError> {
+    let Some(name) = msg.msg_command.as_deref() else {
+        return Ok(Command::Plain);
+    };
+    match find(name) {
+        Some(spec) => (spec.parse)(name, msg.payload.as_ref()),
+        None => Ok(Command::Upstream(name.to_string())),
+    }
+}
+
+/// Проверяет, может ли отправитель дать эту команду с таким target.
+/// Команды не из реестра, которые комната пересылает в upstream,
+/// разрешены тем же ролям, что меняют сцену. Учитывается управление
+/// комнатой: удалённому ничего не доступно, заглушённому — только чтение,
+/// заблокированную сцену меняют только учитель, ADMIN и ученик, которому
+/// передано управление.
+pub fn authorize(
+    msg: &IncomingMessage,
+    command: &Command,
+    config: &RoomConfig,
+    control: &Control,
+) -> Result<(), ApiError> {
+    let role = msg.sender.sender_type.as_str();
+    let forbidden = |field: &str, message: String| {
+        ApiError::new(ErrorCode::ForbiddenCommand, message).field(field)
+    };
+    let perms = permissions::for_role(role).ok_or_else(|| {
+        forbidden(
+            "sender.type",
+            format!("Роль {} не может отправлять сообщения", role),
+        )
+    })?;
+
+    let spec = msg.msg_command.as_deref().and_then(find);
+    let sender = msg.sender.id.as_str();
+    control.admit(sender)?;
+    if control.is_muted(sender) && !spec.is_some_and(|s| s.to_server && !s.mutates) {
+        return Err(ApiError::new(
+            ErrorCode::Muted,
+            format!("Пользователь {} заглушён учителем", sender),
+        )
+        .field("sender.id"));
+    }
+    match command {
+        Command::Plain => {
+            if !perms.plain {
+                return Err(forbidden(
+                    "command",
+                    format!("Роль {} не может отправлять сообщения без команды", role),
+                ));
+            }
+        }
+        Command::Upstream(name) => {
+            if !config.forwards(name) {
+                return Err(ApiError::new(
+                    ErrorCode::UnknownCommand,
+                    format!("Неизвестная команда {}", name),
+                )
+                .field("command"));
+            }
+            // как и сцену, команды ведущего отправляет и ученик с переданным управлением
+            let controls = control.controls(sender);
+            if !EDITORS.contains(&role) && !controls {
+                return Err(forbidden(
+                    "command",
+                    format!("Роль {} не может отправлять команду {}", role, name),
+                ));
+            }
+            if control.locked && !EDITORS.contains(&role) && !controls {
+                return Err(forbidden(
+                    "command",
+                    format!("Сцена заблокирована: команда {} недоступна", name),
+                ));
+            }
+        }
+        _ => {
+            if let Some(spec) = spec {
+                // ученик, которому передано управление, меняет сцену как учитель
+                let scene = matches!(command, Command::Scene(_));
+                let controls = scene && control.controls(sender);
+                if !spec.roles.contains(&role) && !controls {
+                    return Err(forbidden(
+                        "command",
+                        format!("Роль {} не может отправлять команду {}", role, spec.name),
+                    ));
+                }
+                if scene && spec.mutates && control.locked && !EDITORS.contains(&role) && !controls
+                {
+                    return Err(forbidden(
+                        "command",
+                        format!("Сцена заблокирована: команда {} недоступна", spec.name),
+                    ));
+                }
+                if matches!(command, Command::Hello(_) | Command::Resume(_)) {
+                    return Err(forbidden(
+                        "command",
+                        format!("Команда {} доступна только по WebSocket", spec.name),
+                    ));
+                }
+            }
+        }
+    }
+
+    if spec.is_some_and(|s| s.to_server) {
+        return Ok(());
+    }
+    let scope = msg.target.as_ref().map_or("all", |t| t.scope.as_str());
+    if !perms.scopes.contains(&scope) {
+        return Err(ApiError::new(
+            ErrorCode::ForbiddenTarget,
+            format!(
+                "Роль {} не может адресовать сообщения по scope {}",
+                role, scope
+            ),
+        )
+        .field("target.scope"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Код ошибки (или `None`) для сообщения роли с командой и target.scope
+    fn check(role: &str, command: Option<&str>, payload: Value, scope: &str) -> Option<ErrorCode> {
+        check_with(role, command, payload, scope, &Control::default())
+    }
+
+    /// То же при заданном состоянии управления комнатой (отправитель — user_7)
+    fn check_with(
+        role: &str,
+        command: Option<&str>,
+        payload: Value,
+        scope: &str,
+        control: &Control,
+    ) -> Option<ErrorCode> {
+        let config = RoomConfig::parse(
+            "room = room_1\n\
+             teacher = user_1\n\
+             authorised_students = user_2\n\
+             sign_key = secret\n\
+             upstream = http://127.0.0.1:1/command\n\
+             upstream_commands = CHECK_ANSWER\n",
+        )
+        .unwrap();
+        let msg: IncomingMessage = serde_json::from_value(json!({
+            "room_id": "room_1",
+            "sender": {"id": "user_7", "type": role},
+            "command": command,
+            "payload": payload,
+            "target": {"scope": scope, "ids": ["user_1"], "types": ["учитель"]},
+        }))
+        .unwrap();
+        parse(&msg)
+            .and_then(|command| authorize(&msg, &command, &config, control))
+            .err()
+            .map(|e| e.code)
+    }
+
+    #[test]
+    fn plain_messages_and_scopes_follow_permissions_table() {
+        let forbidden_command = Some(ErrorCode::ForbiddenCommand);
+        let forbidden_target = Some(ErrorCode::ForbiddenTarget);
+        for role in ["учитель", "ADMIN"] {
+            for scope in ["all", "type", "ids"] {
+                assert_eq!(
+                    check(role, None, Value::Null, scope),
+                    None,
+                    "{role} {scope}"
+                );
+            }
+        }
+        assert_eq!(check("ученик", None, Value::Null, "all"), None);
+        assert_eq!(check("ученик", None, Value::Null, "ids"), None);
+        assert_eq!(check("ученик", None, Value::Null, "type"), forbidden_target);
+        assert_eq!(
+            check("наблюдатель", None, Value::Null, "all"),
+            forbidden_command
+        );
+        assert_eq!(check("server", None, Value::Null, "all"), forbidden_command);
+        assert_eq!(
+            check("директор", None, Value::Null, "all"),
+            forbidden_command
+        );
+    }
+
+    #[test]
+    fn commands_follow_registry_roles() {
+        let cube = json!({"id": "c1"});
+        let forbidden = Some(ErrorCode::ForbiddenCommand);
+        let cases = [
+            (
+                "GET_STATE",
+                Value::Null,
+                ["учитель", "ADMIN", "ученик", "наблюдатель"].as_slice(),
+            ),
+            ("SELECT_CUBE", cube.clone(), &["учитель", "ADMIN", "ученик"]),
+            ("REMOVE_CUBE", cube.clone(), &["учитель", "ADMIN"]),
+            ("RESET_STATE", Value::Null, &["учитель", "ADMIN"]),
+            ("KICK", json!({"id": "user_2"}), &["учитель", "ADMIN"]),
+            ("LOCK_SCENE", Value::Null, &["учитель", "ADMIN"]),
+            ("CHECK_ANSWER", Value::Null, &["учитель", "ADMIN"]),
+        ];
+        for (command, payload, allowed) in cases {
+            for role in ["учитель", "ADMIN", "ученик", "наблюдатель"] {
+                let expected = if allowed.contains(&role) {
+                    None
+                } else {
+                    forbidden
+                };
+                let got = check(role, Some(command), payload.clone(), "all");
+                assert_eq!(got, expected, "{role} {command}");
+            }
+        }
+        assert_eq!(
+            check("учитель", Some("NOPE"), Value::Null, "all"),
+            Some(ErrorCode::UnknownCommand)
+        );
+        assert_eq!(
+            check("учитель", Some("HELLO"), json!({}), "all"),
+            forbidden,
+            "HELLO только по WS"
+        );
+    }
+
+    #[test]
+    fn controller_forwards_upstream_commands_like_the_scene() {
+        let mut control = Control {
+            controller: Some("user_7".to_string()),
+            ..Control::default()
+        };
+        let check_answer = |control: &Control| {
+            check_with("ученик", Some("CHECK_ANSWER"), Value::Null, "all", control)
+        };
+        assert_eq!(check_answer(&control), None);
+        control.locked = true;
+        assert_eq!(check_answer(&control), None);
+        control.controller = Some("user_2".to_string());
+        assert_eq!(check_answer(&control), Some(ErrorCode::ForbiddenCommand));
+    }
+}
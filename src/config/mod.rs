@@ -1,10 +1,45 @@
+pub mod server;
+
+use crate::outbox::SlowConsumerPolicy;
+use crate::session::DuplicatePolicy;
 use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// таймаут ответа upstream по умолчанию, мс
+const DEFAULT_UPSTREAM_TIMEOUT_MS: u64 = 5000;
+/// через сколько простоя LP‑сессия закрывается, мс
+const DEFAULT_LP_SESSION_TIMEOUT_MS: u64 = 60000;
+/// срок жизни токена доступа, мс
+const DEFAULT_TOKEN_TTL_MS: u64 = 15 * 60 * 1000;
+/// сколько сообщений ждёт отправки одному WS/SSE подписчику
+const DEFAULT_SUBSCRIBER_QUEUE: usize = 256;
 
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct RoomConfig {
     pub room: String,
     pub teacher: String,
     pub authorised_students: Vec<String>,
     pub sign_key: String,
+    /// URL более глубокого бэкенда, куда пересылаются команды ведущего
+    pub upstream: Option<String>,
+    /// Какие команды пересылать в upstream
+    pub upstream_commands: Vec<String>,
+    pub upstream_timeout: Duration,
+    /// LP‑клиент, не опрашивавший столько времени, считается ушедшим,
+    /// а его очередь сообщений удаляется
+    pub lp_session_timeout: Duration,
+    /// Сколько действует токен доступа, выданный /login
+    pub token_ttl: Duration,
+    /// Повторные подключения одного пользователя
+    pub duplicate_sessions: DuplicatePolicy,
+    /// Размер очереди исходящих сообщений WS/SSE подписчика
+    pub subscriber_queue: usize,
+    /// Что делать с подписчиком, чья очередь заполнена
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Окно схлопывания перетаскиваний (MOVE_CUBE): за окно по каждому
+    /// объекту рассылается только последнее положение. `None` — выключено
+    pub coalesce_window: Option<Duration>,
 }
 
 impl RoomConfig {
@@ -12,14 +47,53 @@ impl RoomConfig {
     /// room = room_568491
     /// teacher = user_1
     /// authorised_students = user_2, user_3, user_4
-    pub fn load_from_file(path: &str) -> Self {
-        // Вариант: мы сразу же паникуем, если файл не найден или некорректный формат
+    ///
+    /// Необязательные ключи для пересылки команд в более глубокий бэкенд:
+    /// upstream = http://127.0.0.1:7071/command
+    /// upstream_commands = BUILD, CHECK_ANSWER
+    /// upstream_timeout_ms = 5000
+    ///
+    /// Простой LP‑сессии до её удаления:
+    /// lp_session_timeout_ms = 60000
+    ///
+    /// Срок жизни токена доступа из /login:
+    /// token_ttl_ms = 900000
+    ///
+    /// Повторные подключения одного пользователя (allow — по умолчанию,
+    /// kick_old — новое подключение закрывает прежние):
+    /// duplicate_sessions = kick_old
+    ///
+    /// Очередь исходящих сообщений WS/SSE подписчика и политика при её переполнении
+    /// (drop_oldest — по умолчанию, coalesce, disconnect):
+    /// subscriber_queue = 256
+    /// slow_consumer = coalesce
+    ///
+    /// Окно схлопывания перетаскиваний кубиков (0 — по умолчанию, выключено):
+    /// coalesce_window_ms = 50
+    ///
+    /// Ошибки чтения и формата возвращаются текстом — один битый файл
+    /// не должен ронять сервер с остальными комнатами.
+    pub fn load_from_file(path: &Path) -> Result<Self, String> {
         let content = fs::read_to_string(path)
-            .unwrap_or_else(|e| panic!("Failed to read config file `{}`: {:?}", path, e));
+            .map_err(|e| format!("Failed to read config file `{}`: {}", path.display(), e))?;
+        Self::parse(&content)
+    }
+
+    /// Разбирает содержимое `*.room` файла (формат — см. `load_from_file`)
+    pub fn parse(content: &str) -> Result<Self, String> {
         let mut room = None;
         let mut teacher = None;
         let mut authorised_students = None;
         let mut sign_key = None;
+        let mut upstream = None;
+        let mut upstream_commands = Vec::new();
+        let mut upstream_timeout = Duration::from_millis(DEFAULT_UPSTREAM_TIMEOUT_MS);
+        let mut lp_session_timeout = Duration::from_millis(DEFAULT_LP_SESSION_TIMEOUT_MS);
+        let mut token_ttl = Duration::from_millis(DEFAULT_TOKEN_TTL_MS);
+        let mut duplicate_sessions = DuplicatePolicy::default();
+        let mut subscriber_queue = DEFAULT_SUBSCRIBER_QUEUE;
+        let mut slow_consumer = SlowConsumerPolicy::default();
+        let mut coalesce_window = None;
 
         for (lineno, raw_line) in content.lines().enumerate() {
             let line = raw_line.trim();
@@ -41,42 +115,123 @@ impl RoomConfig {
                         sign_key = Some(val.to_string());
                     }
                     "authorised_students" => {
-                        // Разделяем по запятым, удаляем пробелы
-                        let vec = val
-                            .split(',')
-                            .map(|s| s.trim().to_string())
-                            .filter(|s| !s.is_empty())
-                            .collect::<Vec<_>>();
-                        authorised_students = Some(vec);
+                        authorised_students = Some(split_list(val));
+                    }
+                    "upstream" => {
+                        upstream = Some(val.to_string());
+                    }
+                    "upstream_commands" => {
+                        upstream_commands = split_list(val);
+                    }
+                    "upstream_timeout_ms" => {
+                        let ms = val.parse::<u64>().map_err(|e| {
+                            format!("Bad upstream_timeout_ms at line {}: {}", lineno + 1, e)
+                        })?;
+                        upstream_timeout = Duration::from_millis(ms);
+                    }
+                    "lp_session_timeout_ms" => {
+                        let ms = val.parse::<u64>().map_err(|e| {
+                            format!("Bad lp_session_timeout_ms at line {}: {}", lineno + 1, e)
+                        })?;
+                        lp_session_timeout = Duration::from_millis(ms);
+                    }
+                    "token_ttl_ms" => {
+                        let ms = val
+                            .parse::<u64>()
+                            .ok()
+                            .filter(|ms| *ms > 0)
+                            .ok_or_else(|| {
+                                format!("Bad token_ttl_ms at line {}: `{}`", lineno + 1, val)
+                            })?;
+                        token_ttl = Duration::from_millis(ms);
+                    }
+                    "subscriber_queue" => {
+                        subscriber_queue = val
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|n| *n > 0)
+                            .ok_or_else(|| {
+                                format!("Bad subscriber_queue at line {}: `{}`", lineno + 1, val)
+                            })?;
+                    }
+                    "coalesce_window_ms" => {
+                        let ms = val.parse::<u64>().map_err(|e| {
+                            format!("Bad coalesce_window_ms at line {}: {}", lineno + 1, e)
+                        })?;
+                        coalesce_window = (ms > 0).then(|| Duration::from_millis(ms));
+                    }
+                    "slow_consumer" => {
+                        slow_consumer = SlowConsumerPolicy::parse(val)
+                            .map_err(|e| format!("{} at line {}", e, lineno + 1))?;
+                    }
+                    "duplicate_sessions" => {
+                        duplicate_sessions = DuplicatePolicy::parse(val)
+                            .map_err(|e| format!("{} at line {}", e, lineno + 1))?;
                     }
                     other => {
-                        panic!(
+                        return Err(format!(
                             "Unknown key `{}` in config file at line {}",
                             other,
                             lineno + 1
-                        );
+                        ));
                     }
                 }
             } else {
-                panic!(
+                return Err(format!(
                     "Bad line in config (no '=') at {}: `{}`",
                     lineno + 1,
                     raw_line
-                );
+                ));
             }
         }
 
         // Убедимся, что все поля заданы:
-        let room = room.unwrap_or_else(|| panic!("`room` is missing in config"));
-        let teacher = teacher.unwrap_or_else(|| panic!("`teacher` is missing in config"));
-        let authorised_students = authorised_students.unwrap_or_else(Vec::new);
-        let sign_key = sign_key.unwrap_or_else(|| panic!("`sign_key` is missing in config"));
+        let room = room.ok_or("`room` is missing in config")?;
+        let teacher = teacher.ok_or("`teacher` is missing in config")?;
+        let authorised_students = authorised_students.unwrap_or_default();
+        let sign_key = sign_key.ok_or("`sign_key` is missing in config")?;
 
-        RoomConfig {
+        Ok(RoomConfig {
             room,
             teacher,
             authorised_students,
             sign_key,
+            upstream,
+            upstream_commands,
+            upstream_timeout,
+            lp_session_timeout,
+            token_ttl,
+            duplicate_sessions,
+            subscriber_queue,
+            slow_consumer,
+            coalesce_window,
+        })
+    }
+}
+
+/// Разделяем по запятым, удаляем пробелы
+fn split_list(val: &str) -> Vec<String> {
+    val.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+impl RoomConfig {
+    /// Нужно ли пересылать команду в upstream
+    pub fn forwards(&self, command: &str) -> bool {
+        self.upstream.is_some() && self.upstream_commands.iter().any(|c| c == command)
+    }
+
+    /// Может ли пользователь находиться в комнате с указанной ролью:
+    /// "учитель" — только teacher, "ученик" — только из authorised_students,
+    /// "наблюдатель" и "ADMIN" — любой, кому выдана подпись.
+    pub fn role_allowed(&self, id: &str, role: &str) -> bool {
+        match role {
+            "учитель" => id == self.teacher,
+            "ученик" => self.authorised_students.iter().any(|s| s == id),
+            "наблюдатель" | "ADMIN" => true,
+            _ => false,
         }
     }
 }
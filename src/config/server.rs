@@ -0,0 +1,540 @@
+use crate::presence::Transport;
+use clap::Parser;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// конфиг сервера, который читается без `--config`, если он есть
+static DEFAULT_CONFIG_FILE: &str = "server.toml";
+/// каталог с `*.room` файлами по умолчанию
+static DEFAULT_ROOMS_DIR: &str = "files";
+/// каталог для истории и сцен комнат по умолчанию
+static DEFAULT_DATA_DIR: &str = "data";
+static DEFAULT_HOST: &str = "127.0.0.1";
+static DEFAULT_PORT: u16 = 7070;
+
+/// сколько опрос LP ждёт новых сообщений, мс
+const DEFAULT_LP_POLL_TIMEOUT_MS: u64 = 30_000;
+/// keep-alive SSE‑потока, мс
+const DEFAULT_SSE_KEEP_ALIVE_MS: u64 = 60_000;
+/// как часто перечитывать каталог комнат, мс
+const DEFAULT_ROOMS_POLL_INTERVAL_MS: u64 = 2_000;
+/// как часто WS‑актор шлёт ping протокола, мс
+const DEFAULT_WS_PING_INTERVAL_MS: u64 = 10_000;
+/// сколько WS‑клиент может молчать, прежде чем соединение закроется, мс
+const DEFAULT_WS_PONG_TIMEOUT_MS: u64 = 30_000;
+/// прикладной PING по WS и пустое событие SSE, мс
+const DEFAULT_APP_PING_INTERVAL_MS: u64 = 15_000;
+/// транспорты с прикладным PING по умолчанию
+const DEFAULT_APP_PING: &[Transport] = &[Transport::Ws, Transport::Sse];
+
+/// Флаги командной строки. Каждый можно задать и переменной окружения;
+/// приоритет: флаг > переменная окружения > файл конфига > значение по умолчанию.
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Сервер комнат 3D‑сцены: WebSocket, SSE и Long Polling"
+)]
+pub struct Cli {
+    /// Каталог с *.room файлами [по умолчанию: files]
+    #[arg(env = "CUBECAST_ROOMS_DIR")]
+    pub rooms_dir: Option<String>,
+    /// Каталог для истории и сцен комнат [по умолчанию: data]
+    #[arg(env = "CUBECAST_DATA_DIR")]
+    pub data_dir: Option<String>,
+    /// Файл конфига сервера (TOML) [по умолчанию: server.toml, если есть]
+    #[arg(long, short, env = "CUBECAST_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Адрес, на котором слушает сервер [по умолчанию: 127.0.0.1]
+    #[arg(long, env = "CUBECAST_HOST")]
+    pub host: Option<String>,
+    /// Порт [по умолчанию: 7070]
+    #[arg(long, env = "CUBECAST_PORT")]
+    pub port: Option<u16>,
+    /// PEM‑сертификат (цепочка) для https:// и wss://
+    #[arg(long, env = "CUBECAST_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM‑ключ сертификата
+    #[arg(long, env = "CUBECAST_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// Сколько опрос LP ждёт новых сообщений, мс
+    #[arg(long, env = "CUBECAST_LP_POLL_TIMEOUT_MS")]
+    pub lp_poll_timeout_ms: Option<u64>,
+    /// Keep-alive SSE‑потока, мс
+    #[arg(long, env = "CUBECAST_SSE_KEEP_ALIVE_MS")]
+    pub sse_keep_alive_ms: Option<u64>,
+    /// Как часто перечитывать каталог комнат, мс
+    #[arg(long, env = "CUBECAST_ROOMS_POLL_INTERVAL_MS")]
+    pub rooms_poll_interval_ms: Option<u64>,
+    /// Интервал ping протокола WebSocket, мс
+    #[arg(long, env = "CUBECAST_WS_PING_INTERVAL_MS")]
+    pub ws_ping_interval_ms: Option<u64>,
+    /// Сколько WS‑клиент может молчать, прежде чем соединение закроется, мс
+    #[arg(long, env = "CUBECAST_WS_PONG_TIMEOUT_MS")]
+    pub ws_pong_timeout_ms: Option<u64>,
+    /// Интервал прикладного PING, мс
+    #[arg(long, env = "CUBECAST_APP_PING_INTERVAL_MS")]
+    pub app_ping_interval_ms: Option<u64>,
+    /// Транспорты с прикладным PING через запятую: ws, sse; пусто — выключен
+    #[arg(long, env = "CUBECAST_APP_PING")]
+    pub app_ping: Option<String>,
+    /// Origin браузерных клиентов через запятую: https://example.com; * — любые
+    #[arg(long, env = "CUBECAST_ALLOWED_ORIGINS")]
+    pub allowed_origins: Option<String>,
+    /// Открывать /ws только с заголовком Origin (отсекает не‑браузерных клиентов)
+    #[arg(long, env = "CUBECAST_REQUIRE_ORIGIN", num_args = 0..=1, default_missing_value = "true")]
+    pub require_origin: Option<bool>,
+}
+
+/// Файл конфига как он записан; отсутствующие ключи берутся по умолчанию
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    host: Option<String>,
+    port: Option<u16>,
+    rooms_dir: Option<String>,
+    data_dir: Option<String>,
+    tls: Option<TlsFile>,
+    #[serde(default)]
+    timeouts: TimeoutsFile,
+    #[serde(default)]
+    liveness: LivenessFile,
+    #[serde(default)]
+    cors: CorsFile,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsFile {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsFile {
+    lp_poll_timeout_ms: Option<u64>,
+    sse_keep_alive_ms: Option<u64>,
+    rooms_poll_interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LivenessFile {
+    ws_ping_interval_ms: Option<u64>,
+    ws_pong_timeout_ms: Option<u64>,
+    app_ping_interval_ms: Option<u64>,
+    app_ping: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct CorsFile {
+    allowed_origins: Option<Vec<String>>,
+    require_origin: Option<bool>,
+}
+
+/// Сертификат и ключ для TLS
+#[derive(Clone, Debug)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Интервалы и таймауты транспортов
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Сколько опрос LP ждёт новых сообщений, прежде чем вернуть пустую пачку
+    pub lp_poll: Duration,
+    pub sse_keep_alive: Duration,
+    /// Как часто перечитывать каталог комнат
+    pub rooms_poll: Duration,
+}
+
+/// Проверка, что клиенты живы. WS проверяется ping/pong протокола:
+/// клиент, молчащий дольше `ws_pong_timeout`, отключается и снимается
+/// с регистрации. Прикладной PING (сообщение PING по WS, пустое событие
+/// по SSE) нужен клиентам, которые сами следят за соединением, и шлётся
+/// только перечисленным транспортам.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    pub ws_ping_interval: Duration,
+    pub ws_pong_timeout: Duration,
+    pub app_ping_interval: Duration,
+    pub app_ping: Vec<Transport>,
+}
+
+impl Liveness {
+    pub fn app_ping(&self, transport: Transport) -> bool {
+        self.app_ping.contains(&transport)
+    }
+}
+
+/// Origin, с которых браузер может обращаться к HTTP‑маршрутам и открывать WS.
+/// Запросы без заголовка `Origin` (не из браузера) не ограничиваются,
+/// если не включён `require_origin`.
+#[derive(Clone, Debug, Default)]
+pub struct AllowedOrigins {
+    /// Любой origin (`*`) — для разработки
+    pub any: bool,
+    /// `scheme://host[:port]` без завершающего `/`
+    pub origins: Vec<String>,
+    /// WS без `Origin` отклоняется: браузер присылает его всегда, так что
+    /// открыть /ws смогут только браузеры с разрешённых сайтов
+    pub require_origin: bool,
+}
+
+impl AllowedOrigins {
+    pub fn allows(&self, origin: &str) -> bool {
+        self.any || self.origins.iter().any(|o| o.eq_ignore_ascii_case(origin))
+    }
+}
+
+/// Список origin из флага или файла; пустой список не пускает ни один origin
+fn allowed_origins<'a>(list: impl IntoIterator<Item = &'a str>) -> Result<AllowedOrigins, String> {
+    let mut allowed = AllowedOrigins::default();
+    for origin in list.into_iter().map(str::trim).filter(|s| !s.is_empty()) {
+        if origin == "*" {
+            allowed.any = true;
+            continue;
+        }
+        let origin = origin.trim_end_matches('/');
+        let host = origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"))
+            .ok_or_else(|| format!("Origin `{}` must start with http:// or https://", origin))?;
+        if host.is_empty() || host.contains('/') {
+            return Err(format!(
+                "Origin `{}` must be scheme://host[:port] without a path",
+                origin
+            ));
+        }
+        allowed.origins.push(origin.to_string());
+    }
+    Ok(allowed)
+}
+
+/// Настройки сервера: адрес, каталоги, TLS, таймауты, проверка клиентов и origin
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub rooms_dir: String,
+    pub data_dir: String,
+    pub tls: Option<Tls>,
+    pub timeouts: Timeouts,
+    pub liveness: Liveness,
+    pub origins: AllowedOrigins,
+}
+
+/// Интервал в мс из флага, файла или по умолчанию; ноль не допускается
+fn duration_ms(
+    name: &str,
+    cli: Option<u64>,
+    file: Option<u64>,
+    default: u64,
+) -> Result<Duration, String> {
+    match cli.or(file).unwrap_or(default) {
+        0 => Err(format!("`{}` must be greater than 0", name)),
+        ms => Ok(Duration::from_millis(ms)),
+    }
+}
+
+/// Транспорты прикладного PING; пустой список выключает его
+fn app_ping_transports<'a>(
+    list: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<Transport>, String> {
+    list.into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| match Transport::parse(s)? {
+            Transport::LongPolling => Err("app_ping is only sent over `ws` and `sse`".to_string()),
+            transport => Ok(transport),
+        })
+        .collect()
+}
+
+impl ServerConfig {
+    /// Собирает конфиг из флагов, переменных окружения и файла.
+    /// Файл из `--config` обязан существовать, `server.toml` — только если он есть.
+    /// Ошибки возвращаются текстом для вывода при старте.
+    pub fn load(cli: Cli) -> Result<Self, String> {
+        let file = match &cli.config {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => ConfigFile::default(),
+        };
+
+        let tls = match (cli.tls_cert, cli.tls_key, file.tls) {
+            (Some(cert), Some(key), _) => Some(Tls { cert, key }),
+            (None, None, Some(tls)) => Some(Tls {
+                cert: tls.cert,
+                key: tls.key,
+            }),
+            (None, None, None) => None,
+            _ => return Err("TLS needs both `--tls-cert` and `--tls-key`".to_string()),
+        };
+
+        let t = file.timeouts;
+        let timeouts = Timeouts {
+            lp_poll: duration_ms(
+                "lp_poll_timeout_ms",
+                cli.lp_poll_timeout_ms,
+                t.lp_poll_timeout_ms,
+                DEFAULT_LP_POLL_TIMEOUT_MS,
+            )?,
+            sse_keep_alive: duration_ms(
+                "sse_keep_alive_ms",
+                cli.sse_keep_alive_ms,
+                t.sse_keep_alive_ms,
+                DEFAULT_SSE_KEEP_ALIVE_MS,
+            )?,
+            rooms_poll: duration_ms(
+                "rooms_poll_interval_ms",
+                cli.rooms_poll_interval_ms,
+                t.rooms_poll_interval_ms,
+                DEFAULT_ROOMS_POLL_INTERVAL_MS,
+            )?,
+        };
+
+        let l = file.liveness;
+        let app_ping = match (cli.app_ping, l.app_ping) {
+            (Some(list), _) => app_ping_transports(list.split(','))?,
+            (None, Some(list)) => app_ping_transports(list.iter().map(String::as_str))?,
+            (None, None) => DEFAULT_APP_PING.to_vec(),
+        };
+        let liveness = Liveness {
+            ws_ping_interval: duration_ms(
+                "ws_ping_interval_ms",
+                cli.ws_ping_interval_ms,
+                l.ws_ping_interval_ms,
+                DEFAULT_WS_PING_INTERVAL_MS,
+            )?,
+            ws_pong_timeout: duration_ms(
+                "ws_pong_timeout_ms",
+                cli.ws_pong_timeout_ms,
+                l.ws_pong_timeout_ms,
+                DEFAULT_WS_PONG_TIMEOUT_MS,
+            )?,
+            app_ping_interval: duration_ms(
+                "app_ping_interval_ms",
+                cli.app_ping_interval_ms,
+                l.app_ping_interval_ms,
+                DEFAULT_APP_PING_INTERVAL_MS,
+            )?,
+            app_ping,
+        };
+        // иначе pong на очередной ping не успевает прийти до проверки
+        if liveness.ws_pong_timeout <= liveness.ws_ping_interval {
+            return Err(
+                "`ws_pong_timeout_ms` must be greater than `ws_ping_interval_ms`".to_string(),
+            );
+        }
+
+        let mut origins = match (cli.allowed_origins, file.cors.allowed_origins) {
+            (Some(list), _) => allowed_origins(list.split(','))?,
+            (None, Some(list)) => allowed_origins(list.iter().map(String::as_str))?,
+            (None, None) => AllowedOrigins::default(),
+        };
+        origins.require_origin = cli
+            .require_origin
+            .or(file.cors.require_origin)
+            .unwrap_or(false);
+
+        Ok(ServerConfig {
+            host: cli
+                .host
+                .or(file.host)
+                .unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            port: cli.port.or(file.port).unwrap_or(DEFAULT_PORT),
+            rooms_dir: cli
+                .rooms_dir
+                .or(file.rooms_dir)
+                .unwrap_or_else(|| DEFAULT_ROOMS_DIR.to_string()),
+            data_dir: cli
+                .data_dir
+                .or(file.data_dir)
+                .unwrap_or_else(|| DEFAULT_DATA_DIR.to_string()),
+            tls,
+            timeouts,
+            liveness,
+            origins,
+        })
+    }
+
+    /// TLS‑конфиг rustls из PEM‑файлов; `None` — сервер работает без TLS
+    pub fn tls_config(&self) -> Result<Option<rustls::ServerConfig>, String> {
+        let Some(tls) = &self.tls else {
+            return Ok(None);
+        };
+        let certs = CertificateDer::pem_file_iter(&tls.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                format!(
+                    "Failed to read TLS certificate `{}`: {}",
+                    tls.cert.display(),
+                    e
+                )
+            })?;
+        if certs.is_empty() {
+            return Err(format!(
+                "No certificates in TLS certificate file `{}`",
+                tls.cert.display()
+            ));
+        }
+        let key = PrivateKeyDer::from_pem_file(&tls.key)
+            .map_err(|e| format!("Failed to read TLS key `{}`: {}", tls.key.display(), e))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .and_then(|b| b.with_no_client_auth().with_single_cert(certs, key))
+            .map(Some)
+            .map_err(|e| format!("Bad TLS certificate or key: {}", e))
+    }
+}
+
+fn read_file(path: &Path) -> Result<ConfigFile, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read server config `{}`: {}", path.display(), e))?;
+    toml::from_str(&content).map_err(|e| format!("Bad server config `{}`: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Файл конфига во временном каталоге
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "cubecast-server-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn load(args: &[&str]) -> Result<ServerConfig, String> {
+        let cli = Cli::try_parse_from(["CubeCastRouter"].iter().chain(args))
+            .map_err(|e| e.to_string())?;
+        ServerConfig::load(cli)
+    }
+
+    #[test]
+    fn cli_beats_env_beats_file_beats_default() {
+        let path = config_file(
+            "precedence",
+            "host = \"0.0.0.0\"\n\
+             port = 8080\n\
+             data_dir = \"file-data\"\n\
+             rooms_dir = \"file-rooms\"\n\
+             [timeouts]\n\
+             lp_poll_timeout_ms = 1000\n",
+        );
+        let path = path.to_str().unwrap();
+        // переменные окружения задаёт только этот тест
+        std::env::set_var("CUBECAST_PORT", "9090");
+        std::env::set_var("CUBECAST_DATA_DIR", "env-data");
+        let config = load(&["--config", path, "--host", "::1"]).unwrap();
+        let with_flag = load(&["--config", path, "--port", "7171"]).unwrap();
+        std::env::remove_var("CUBECAST_PORT");
+        std::env::remove_var("CUBECAST_DATA_DIR");
+        let _ = fs::remove_file(path);
+
+        assert_eq!(config.host, "::1");
+        assert_eq!(with_flag.port, 7171);
+        assert_eq!(config.port, 9090);
+        assert_eq!(config.data_dir, "env-data");
+        assert_eq!(config.rooms_dir, "file-rooms");
+        assert_eq!(config.timeouts.lp_poll, Duration::from_millis(1000));
+        assert_eq!(
+            config.timeouts.sse_keep_alive,
+            Duration::from_millis(DEFAULT_SSE_KEEP_ALIVE_MS)
+        );
+        assert_eq!(config.liveness.app_ping, DEFAULT_APP_PING);
+        assert!(config.tls.is_none());
+    }
+
+    #[test]
+    fn bad_port_is_reported_readably() {
+        let err = load(&["--port", "http"]).unwrap_err();
+        assert!(err.contains("--port"), "{err}");
+
+        let path = config_file("bad-port", "port = 70000\n");
+        let err = load(&["--config", path.to_str().unwrap()]).unwrap_err();
+        let _ = fs::remove_file(&path);
+        assert!(err.starts_with("Bad server config"), "{err}");
+        assert!(err.contains("port"), "{err}");
+    }
+
+    #[test]
+    fn tls_needs_both_files_and_reports_missing_ones() {
+        let err = load(&["--tls-cert", "cert.pem"]).unwrap_err();
+        assert_eq!(err, "TLS needs both `--tls-cert` and `--tls-key`");
+
+        let config = load(&[
+            "--tls-cert",
+            "/nonexistent/cert.pem",
+            "--tls-key",
+            "key.pem",
+        ])
+        .unwrap();
+        let err = config.tls_config().unwrap_err();
+        assert!(
+            err.starts_with("Failed to read TLS certificate `/nonexistent/cert.pem`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn missing_config_file_and_bad_values_are_errors() {
+        let err = load(&["--config", "/nonexistent/server.toml"]).unwrap_err();
+        assert!(
+            err.starts_with("Failed to read server config `/nonexistent/server.toml`"),
+            "{err}"
+        );
+        let err = load(&["--lp-poll-timeout-ms", "0"]).unwrap_err();
+        assert_eq!(err, "`lp_poll_timeout_ms` must be greater than 0");
+        let err = load(&[
+            "--ws-ping-interval-ms",
+            "5000",
+            "--ws-pong-timeout-ms",
+            "5000",
+        ])
+        .unwrap_err();
+        assert!(err.contains("ws_pong_timeout_ms"), "{err}");
+    }
+
+    #[test]
+    fn allowed_origins_are_normalised_and_validated() {
+        let allowed =
+            allowed_origins(" https://school.example/ , http://localhost:8080,".split(','))
+                .unwrap();
+        assert!(!allowed.any);
+        assert_eq!(
+            allowed.origins,
+            vec!["https://school.example", "http://localhost:8080"]
+        );
+        assert!(allowed.allows("http://localhost:8080"));
+        assert!(!allowed.allows("http://localhost:8081"));
+        assert!(allowed_origins(["*"])
+            .unwrap()
+            .allows("https://any.example"));
+        assert!(allowed_origins([]).unwrap().origins.is_empty());
+
+        let err = allowed_origins(["school.example"]).unwrap_err();
+        assert_eq!(
+            err,
+            "Origin `school.example` must start with http:// or https://"
+        );
+        let err = allowed_origins(["https://school.example/app"]).unwrap_err();
+        assert!(err.contains("without a path"), "{err}");
+    }
+}
@@ -0,0 +1,238 @@
+use crate::config::RoomConfig;
+use crate::errors::{ApiError, ErrorCode};
+use crate::permissions::PRIVILEGED_ROLES;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// Управление комнатой, которое ведёт учитель (или ADMIN): кто заглушён,
+/// кто удалён из комнаты, заблокирована ли сцена и кому из учеников
+/// временно передано управление.
+/// Живёт, пока работает сервер; изменения рассылаются событием ROOM_CONTROL.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Control {
+    /// Сцена только для чтения: ученики не могут её менять
+    pub locked: bool,
+    /// Чьи команды и сообщения отбрасываются
+    pub muted: BTreeSet<String>,
+    /// Удалённые (KICK): не могут войти и писать, пока их не вернут (UNKICK)
+    pub kicked: BTreeSet<String>,
+    /// Ученик, которому передано управление: меняет сцену наравне с учителем
+    pub controller: Option<String>,
+}
+
+/// Команды управления комнатой (разбираются реестром `commands`)
+#[derive(Clone, Debug)]
+pub enum ControlCommand {
+    Kick {
+        id: String,
+    },
+    Unkick {
+        id: String,
+    },
+    Mute {
+        id: String,
+    },
+    Unmute {
+        id: String,
+    },
+    Lock,
+    Unlock,
+    /// `None` — управление возвращается учителю
+    PassControl {
+        id: Option<String>,
+    },
+}
+
+impl ControlCommand {
+    /// Имя команды — оно же `action` в ROOM_CONTROL
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlCommand::Kick { .. } => "KICK",
+            ControlCommand::Unkick { .. } => "UNKICK",
+            ControlCommand::Mute { .. } => "MUTE",
+            ControlCommand::Unmute { .. } => "UNMUTE",
+            ControlCommand::Lock => "LOCK_SCENE",
+            ControlCommand::Unlock => "UNLOCK_SCENE",
+            ControlCommand::PassControl { .. } => "PASS_CONTROL",
+        }
+    }
+
+    /// Пользователь, к которому относится команда
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            ControlCommand::Kick { id }
+            | ControlCommand::Unkick { id }
+            | ControlCommand::Mute { id }
+            | ControlCommand::Unmute { id } => Some(id),
+            ControlCommand::PassControl { id } => id.as_deref(),
+            ControlCommand::Lock | ControlCommand::Unlock => None,
+        }
+    }
+}
+
+fn invalid(message: String) -> ApiError {
+    ApiError::new(ErrorCode::InvalidPayload, message).field("payload.id")
+}
+
+impl Control {
+    pub fn is_muted(&self, id: &str) -> bool {
+        self.muted.contains(id)
+    }
+
+    /// Пускать ли пользователя в комнату: удалённого — нет, пока его не вернут
+    pub fn admit(&self, id: &str) -> Result<(), ApiError> {
+        if self.kicked.contains(id) {
+            return Err(ApiError::new(
+                ErrorCode::Kicked,
+                format!("Учитель удалил пользователя {} из комнаты", id),
+            )
+            .field("sender.id"));
+        }
+        Ok(())
+    }
+
+    /// Передано ли пользователю управление сценой
+    pub fn controls(&self, id: &str) -> bool {
+        self.controller.as_deref() == Some(id)
+    }
+
+    /// Применяет команду отправителя `by`. KICK только запоминает удалённого:
+    /// его подключения закрывает комната.
+    /// `target_roles` — роли, с которыми адресат команды сейчас в комнате:
+    /// учителя и ADMIN нельзя удалить или заглушить.
+    pub fn apply(
+        &mut self,
+        cmd: &ControlCommand,
+        by: &str,
+        target_roles: &[String],
+        config: &RoomConfig,
+    ) -> Result<(), ApiError> {
+        if cmd.user() == Some(by) {
+            return Err(invalid(format!(
+                "Команду {} нельзя применить к себе",
+                cmd.name()
+            )));
+        }
+        if let ControlCommand::Kick { id } | ControlCommand::Mute { id } = cmd {
+            let privileged = *id == config.teacher
+                || target_roles
+                    .iter()
+                    .any(|role| PRIVILEGED_ROLES.contains(&role.as_str()));
+            if privileged {
+                return Err(ApiError::new(
+                    ErrorCode::ForbiddenCommand,
+                    format!(
+                        "Команду {} нельзя применить к учителю или администратору {}",
+                        cmd.name(),
+                        id
+                    ),
+                )
+                .field("payload.id"));
+            }
+        }
+        match cmd {
+            ControlCommand::Kick { id } => {
+                self.kicked.insert(id.clone());
+            }
+            ControlCommand::Unkick { id } => {
+                self.kicked.remove(id);
+            }
+            ControlCommand::Mute { id } => {
+                self.muted.insert(id.clone());
+            }
+            ControlCommand::Unmute { id } => {
+                self.muted.remove(id);
+            }
+            ControlCommand::Lock => self.locked = true,
+            ControlCommand::Unlock => self.locked = false,
+            ControlCommand::PassControl { id: Some(id) } => {
+                if !config.authorised_students.contains(id) {
+                    return Err(invalid(format!(
+                        "Управление можно передать только ученику комнаты, {} не из них",
+                        id
+                    )));
+                }
+                self.controller = Some(id.clone());
+            }
+            ControlCommand::PassControl { id: None } => self.controller = None,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kicked_user_is_refused_until_unkicked() {
+        let config = RoomConfig::parse(
+            "room = room_1\n\
+             teacher = user_1\n\
+             authorised_students = user_2\n\
+             sign_key = secret\n",
+        )
+        .unwrap();
+        let mut control = Control::default();
+        let kick = ControlCommand::Kick {
+            id: "user_2".to_string(),
+        };
+        control.apply(&kick, "user_1", &[], &config).unwrap();
+        assert_eq!(control.admit("user_2").unwrap_err().code, ErrorCode::Kicked);
+        assert!(control.admit("user_1").is_ok());
+
+        let unkick = ControlCommand::Unkick {
+            id: "user_2".to_string(),
+        };
+        control.apply(&unkick, "user_1", &[], &config).unwrap();
+        assert!(control.admit("user_2").is_ok());
+    }
+
+    #[test]
+    fn teacher_and_admin_cannot_be_kicked_or_muted() {
+        let config = RoomConfig::parse(
+            "room = room_1\n\
+             teacher = user_1\n\
+             authorised_students = user_2\n\
+             sign_key = secret\n",
+        )
+        .unwrap();
+        let mut control = Control::default();
+        let admin = ["ADMIN".to_string()];
+        let commands = [
+            (
+                ControlCommand::Kick {
+                    id: "user_1".to_string(),
+                },
+                "user_9",
+                &[][..],
+            ),
+            (
+                ControlCommand::Mute {
+                    id: "user_1".to_string(),
+                },
+                "user_9",
+                &[][..],
+            ),
+            (
+                ControlCommand::Kick {
+                    id: "user_9".to_string(),
+                },
+                "user_1",
+                &admin[..],
+            ),
+            (
+                ControlCommand::Mute {
+                    id: "user_9".to_string(),
+                },
+                "user_1",
+                &admin[..],
+            ),
+        ];
+        for (cmd, by, roles) in &commands {
+            let err = control.apply(cmd, by, roles, &config).unwrap_err();
+            assert_eq!(err.code, ErrorCode::ForbiddenCommand, "{:?}", cmd);
+        }
+        assert!(control.kicked.is_empty() && control.muted.is_empty());
+    }
+}
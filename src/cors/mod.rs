@@ -0,0 +1,147 @@
+use crate::config::server::AllowedOrigins;
+use crate::errors::{ApiError, ErrorCode};
+use actix_cors::Cors;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+/// CORS для HTTP‑маршрутов: только origin из `allowed_origins`.
+/// Отклонённый origin пишется в лог.
+pub fn cors(origins: &AllowedOrigins) -> Cors {
+    let allowed = origins.clone();
+    let cors = Cors::default()
+        .allowed_origin_fn(move |origin, req| {
+            let origin = origin.to_str().unwrap_or_default();
+            let ok = allowed.allows(origin);
+            if !ok {
+                println!(
+                    "CORS: отклонён origin {} ({} {})",
+                    origin,
+                    req.method,
+                    req.uri.path()
+                );
+            }
+            ok
+        })
+        .allowed_methods(vec!["GET", "POST", "OPTIONS"])
+        .allowed_headers(vec![header::CONTENT_TYPE, header::AUTHORIZATION])
+        // TTL для preflight (в секундах)
+        .max_age(3600);
+    // с `*` ответ получает любой сайт — куки и авторизацию браузера ему не отдаём
+    if origins.any {
+        cors
+    } else {
+        cors.supports_credentials()
+    }
+}
+
+/// Проверка `Origin` при открытии WebSocket: браузер не делает для него
+/// preflight, поэтому CORS его не защищает. Без `Origin` (не браузер)
+/// подключение пропускается, если не включён `require_origin`.
+pub fn check_origin(req: &HttpRequest, origins: &AllowedOrigins) -> Result<(), ApiError> {
+    let Some(origin) = req.headers().get(header::ORIGIN) else {
+        if origins.require_origin {
+            println!(
+                "WebSocket: отклонено подключение без Origin ({})",
+                req.path()
+            );
+            return Err(ApiError::new(
+                ErrorCode::ForbiddenOrigin,
+                "Подключение без Origin не разрешено",
+            ));
+        }
+        return Ok(());
+    };
+    let origin = origin.to_str().unwrap_or_default();
+    if origins.allows(origin) {
+        return Ok(());
+    }
+    println!("WebSocket: отклонён origin {} ({})", origin, req.path());
+    Err(ApiError::new(
+        ErrorCode::ForbiddenOrigin,
+        format!("Origin {} не разрешён", origin),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    fn origins(list: &[&str], require_origin: bool) -> AllowedOrigins {
+        AllowedOrigins {
+            any: list.contains(&"*"),
+            origins: list
+                .iter()
+                .filter(|o| **o != "*")
+                .map(|o| o.to_string())
+                .collect(),
+            require_origin,
+        }
+    }
+
+    fn check(origin: Option<&str>, allowed: &AllowedOrigins) -> Option<ErrorCode> {
+        let mut req = TestRequest::get().uri("/ws");
+        if let Some(origin) = origin {
+            req = req.insert_header((header::ORIGIN, origin));
+        }
+        check_origin(&req.to_http_request(), allowed)
+            .err()
+            .map(|e| e.code)
+    }
+
+    #[test]
+    fn websocket_origin_must_be_allowed() {
+        let forbidden = Some(ErrorCode::ForbiddenOrigin);
+        let allowed = origins(&["https://school.example"], false);
+        assert_eq!(check(Some("https://school.example"), &allowed), None);
+        assert_eq!(check(Some("HTTPS://School.Example"), &allowed), None);
+        assert_eq!(check(Some("https://evil.example"), &allowed), forbidden);
+        // не из браузера
+        assert_eq!(check(None, &allowed), None);
+
+        let strict = origins(&["https://school.example"], true);
+        assert_eq!(check(None, &strict), forbidden);
+        assert_eq!(check(Some("https://school.example"), &strict), None);
+
+        assert_eq!(
+            check(Some("https://evil.example"), &origins(&["*"], false)),
+            None
+        );
+        assert_eq!(
+            check(Some("https://school.example"), &origins(&[], false)),
+            forbidden
+        );
+    }
+
+    #[actix_web::test]
+    async fn preflight_answers_only_allowed_origins() {
+        let app = test::init_service(
+            App::new()
+                .wrap(cors(&origins(&["https://school.example"], false)))
+                .route("/send", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let preflight = |origin: &str| {
+            TestRequest::default()
+                .method(actix_web::http::Method::OPTIONS)
+                .uri("/send")
+                .insert_header((header::ORIGIN, origin))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+                .to_request()
+        };
+        let resp = test::call_service(&app, preflight("https://school.example")).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://school.example"
+        );
+        let resp = test::call_service(&app, preflight("https://evil.example")).await;
+        assert!(resp
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::ClientMessage;
+use std::collections::HashMap;
+
+/// Отложенное обновление объекта и номер окна, в котором оно ждёт рассылки
+struct Pending {
+    window: u64,
+    msg: ClientMessage,
+}
+
+/// Перетаскивания, ждущие конца окна схлопывания: по (команде, id объекта)
+/// хранится только последнее обновление. Первое обновление открывает окно,
+/// по его окончании рассылается то, что пришло последним.
+#[derive(Default)]
+pub struct DragUpdates {
+    pending: HashMap<(String, String), Pending>,
+    /// Порядок открытия окон — в нём же отложенные обновления сбрасываются
+    order: Vec<(String, String)>,
+    next_window: u64,
+}
+
+impl DragUpdates {
+    /// Откладывает обновление. Возвращает номер нового окна, если окно по этому
+    /// объекту только что открылось (вызывающий заводит таймер), иначе `None` —
+    /// обновление заменило ждущее.
+    pub fn hold(&mut self, key: (String, String), msg: ClientMessage) -> Option<u64> {
+        if let Some(pending) = self.pending.get_mut(&key) {
+            pending.msg = msg;
+            return None;
+        }
+        self.next_window += 1;
+        let window = self.next_window;
+        self.order.push(key.clone());
+        self.pending.insert(key, Pending { window, msg });
+        Some(window)
+    }
+
+    /// Окно закрылось: забирает обновление, если оно ещё ждёт именно в этом окне
+    /// (его могли уже сбросить раньше, и по объекту открылось новое окно)
+    pub fn take(&mut self, key: &(String, String), window: u64) -> Option<ClientMessage> {
+        if self.pending.get(key)?.window != window {
+            return None;
+        }
+        self.order.retain(|k| k != key);
+        self.pending.remove(key).map(|p| p.msg)
+    }
+
+    /// Забирает все ждущие обновления в порядке открытия окон —
+    /// перед дискретной командой, чтобы она не обогнала перетаскивание
+    pub fn drain(&mut self) -> Vec<ClientMessage> {
+        let order = std::mem::take(&mut self.order);
+        order
+            .iter()
+            .filter_map(|key| self.pending.remove(key))
+            .map(|p| p.msg)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(id: &str) -> (String, String) {
+        ("MOVE_CUBE".to_string(), id.to_string())
+    }
+
+    fn moved(id: &str, x: f64) -> ClientMessage {
+        let payload = json!({"id": id, "position": {"x": x, "y": 0.0, "z": 0.0}});
+        ClientMessage::server("room_1", None, "MOVE_CUBE", payload)
+    }
+
+    fn x(msg: &ClientMessage) -> f64 {
+        msg.msg.payload.as_ref().unwrap()["position"]["x"]
+            .as_f64()
+            .unwrap()
+    }
+
+    #[test]
+    fn window_keeps_only_the_last_update_per_object() {
+        let mut drag = DragUpdates::default();
+        let window = drag.hold(key("c1"), moved("c1", 1.0)).unwrap();
+        assert_eq!(drag.hold(key("c1"), moved("c1", 2.0)), None);
+        assert_eq!(drag.hold(key("c1"), moved("c1", 3.0)), None);
+        let other = drag.hold(key("c2"), moved("c2", 9.0)).unwrap();
+        assert_ne!(window, other);
+
+        assert_eq!(x(&drag.take(&key("c1"), window).unwrap()), 3.0);
+        // окно закрыто — следующее обновление открывает новое
+        assert!(drag.take(&key("c1"), window).is_none());
+        assert!(drag.hold(key("c1"), moved("c1", 4.0)).unwrap() > other);
+    }
+
+    #[test]
+    fn drain_flushes_in_window_order_and_stales_timers() {
+        let mut drag = DragUpdates::default();
+        let first = drag.hold(key("c2"), moved("c2", 1.0)).unwrap();
+        drag.hold(key("c1"), moved("c1", 2.0)).unwrap();
+        drag.hold(key("c2"), moved("c2", 3.0));
+
+        let flushed: Vec<f64> = drag.drain().iter().map(x).collect();
+        assert_eq!(flushed, vec![3.0, 2.0]);
+        // таймер сброшенного окна ничего не находит, даже если окно открыто заново
+        drag.hold(key("c2"), moved("c2", 5.0)).unwrap();
+        assert!(drag.take(&key("c2"), first).is_none());
+    }
+}
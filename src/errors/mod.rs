@@ -0,0 +1,144 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+
+/// Стабильные коды ошибок: клиенты ветвятся по ним, а не по тексту
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// Сообщение не разбирается (JSON или двоичный кадр)
+    MalformedMessage,
+    /// Сообщение разобрано, но не прошло валидацию
+    InvalidMessage,
+    /// Неизвестная комната
+    InvalidRoom,
+    /// Подпись неверна или пользователь не допущен в комнату
+    Unauthorized,
+    /// Неверный токен продолжения сессии
+    InvalidResumeToken,
+    /// Неверный токен доступа
+    InvalidToken,
+    /// Срок действия токена доступа истёк: нужно войти заново
+    TokenExpired,
+    /// Отправитель не совпадает с подключением
+    SenderMismatch,
+    /// Команды нет в реестре, и комната не пересылает её в upstream
+    UnknownCommand,
+    /// Роль не может отправлять эту команду (или сообщения без команды)
+    ForbiddenCommand,
+    /// Роль не может адресовать сообщения по такому target
+    ForbiddenTarget,
+    /// Отправитель заглушён учителем (MUTE)
+    Muted,
+    /// Учитель удалил пользователя из комнаты (KICK)
+    Kicked,
+    /// Служебный маршрут недоступен роли
+    Forbidden,
+    /// Origin браузера не входит в `allowed_origins` сервера
+    ForbiddenOrigin,
+    /// Некорректный payload команды
+    InvalidPayload,
+    /// Объект уже есть на сцене
+    ObjectExists,
+    /// Объекта нет на сцене
+    ObjectNotFound,
+    /// Upstream не ответил вовремя
+    UpstreamTimeout,
+    /// Upstream недоступен или ответил ошибкой
+    UpstreamError,
+    /// Неизвестная кодировка WS
+    UnsupportedEncoding,
+    Internal,
+}
+
+impl ErrorCode {
+    /// HTTP‑статус для ответов маршрутов
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::MalformedMessage
+            | ErrorCode::InvalidMessage
+            | ErrorCode::InvalidPayload
+            | ErrorCode::UnknownCommand
+            | ErrorCode::UnsupportedEncoding => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRoom => StatusCode::NOT_FOUND,
+            ErrorCode::Unauthorized
+            | ErrorCode::InvalidResumeToken
+            | ErrorCode::InvalidToken
+            | ErrorCode::TokenExpired => StatusCode::UNAUTHORIZED,
+            ErrorCode::SenderMismatch
+            | ErrorCode::ForbiddenCommand
+            | ErrorCode::ForbiddenTarget
+            | ErrorCode::Muted
+            | ErrorCode::Kicked
+            | ErrorCode::Forbidden
+            | ErrorCode::ForbiddenOrigin => StatusCode::FORBIDDEN,
+            ErrorCode::ObjectExists | ErrorCode::ObjectNotFound => StatusCode::CONFLICT,
+            ErrorCode::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::UpstreamError => StatusCode::BAD_GATEWAY,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Единый конверт ошибки для всех транспортов: `{"error": {...}}`.
+/// `message` — пояснение для человека (может отсутствовать, на него
+/// нельзя полагаться), `field` — поле сообщения, из‑за которого ошибка,
+/// `correlation_id` — по нему ошибку можно найти в логе сервера.
+#[derive(Clone, Debug, Serialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub correlation_id: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: Some(message.into()),
+            field: None,
+            correlation_id: uuid::Uuid::new_v4().simple().to_string(),
+        }
+    }
+
+    pub fn field(mut self, field: &str) -> Self {
+        self.field = Some(field.to_string());
+        self
+    }
+
+    /// Тело ответа `{"error": {...}}`; ошибка записывается в лог с её correlation_id
+    pub fn body(&self) -> Value {
+        eprintln!("Ошибка {} [{}]", self, self.correlation_id);
+        serde_json::json!({ "error": self })
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // код в том же виде, что и в JSON
+        let code = serde_json::to_value(self.code).unwrap_or(Value::Null);
+        write!(f, "{}", code.as_str().unwrap_or_default())?;
+        if let Some(field) = &self.field {
+            write!(f, " ({})", field)?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.body())
+    }
+}
@@ -0,0 +1,114 @@
+use crate::ClientMessage;
+use std::collections::VecDeque;
+
+/// Сколько последних сообщений комнаты хранится для повторной доставки
+const HISTORY_CAPACITY: usize = 1000;
+
+/// Журнал разосланных сообщений комнаты.
+/// Каждое сообщение получает возрастающий номер `seq`, по которому
+/// переподключившийся клиент запрашивает пропущенное.
+pub struct History {
+    next_seq: u64,
+    messages: VecDeque<ClientMessage>,
+    capacity: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            next_seq: 1,
+            messages: VecDeque::new(),
+            capacity: HISTORY_CAPACITY,
+        }
+    }
+}
+
+impl History {
+    /// Присваивает сообщению следующий seq и сохраняет его; старые вытесняются.
+    pub fn record(&mut self, msg: &mut ClientMessage) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        msg.msg.seq = Some(seq);
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(msg.clone());
+        seq
+    }
+
+    /// Восстановление после перезапуска: сохранённые сообщения (по возрастанию seq),
+    /// нумерация продолжается с последнего.
+    pub fn restore(&mut self, messages: Vec<ClientMessage>) {
+        let skip = messages.len().saturating_sub(self.capacity);
+        self.messages = messages.into_iter().skip(skip).collect();
+        if let Some(seq) = self.messages.back().and_then(|m| m.msg.seq) {
+            self.next_seq = seq + 1;
+        }
+    }
+
+    /// seq последнего записанного сообщения (0 — история пуста)
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Сообщения с seq больше `since`, начиная с самого старого из сохранившихся
+    pub fn since(&self, since: u64) -> impl Iterator<Item = &ClientMessage> {
+        self.messages
+            .iter()
+            .filter(move |m| m.msg.seq.is_some_and(|seq| seq > since))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn seqs<'a>(messages: impl Iterator<Item = &'a ClientMessage>) -> Vec<u64> {
+        messages.filter_map(|m| m.msg.seq).collect()
+    }
+
+    fn record(history: &mut History, count: usize) {
+        for _ in 0..count {
+            let mut msg = ClientMessage::server("room_1", None, "TEST", Value::Null);
+            history.record(&mut msg);
+        }
+    }
+
+    #[test]
+    fn seq_numbers_grow_from_one() {
+        let mut history = History::default();
+        assert_eq!(history.last_seq(), 0);
+        let mut msg = ClientMessage::server("room_1", None, "TEST", Value::Null);
+        assert_eq!(history.record(&mut msg), 1);
+        assert_eq!(msg.msg.seq, Some(1));
+        record(&mut history, 2);
+        assert_eq!(history.last_seq(), 3);
+        assert_eq!(seqs(history.since(1)), vec![2, 3]);
+        assert!(history.since(3).next().is_none());
+    }
+
+    #[test]
+    fn only_the_last_messages_are_kept() {
+        let mut history = History::default();
+        record(&mut history, HISTORY_CAPACITY + 5);
+        assert_eq!(history.messages.len(), HISTORY_CAPACITY);
+        assert_eq!(history.last_seq(), HISTORY_CAPACITY as u64 + 5);
+        // since старше вытесненных — отдаётся всё, что сохранилось
+        let all = seqs(history.since(0));
+        assert_eq!(all.len(), HISTORY_CAPACITY);
+        assert_eq!(all[0], 6);
+        assert_eq!(seqs(history.since(1003)), vec![1004, 1005]);
+    }
+
+    #[test]
+    fn restore_continues_numbering() {
+        let mut saved = History::default();
+        record(&mut saved, 3);
+        let mut history = History::default();
+        history.restore(saved.since(0).cloned().collect());
+        assert_eq!(history.last_seq(), 3);
+        record(&mut history, 1);
+        assert_eq!(seqs(history.since(2)), vec![3, 4]);
+    }
+}
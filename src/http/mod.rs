@@ -1,103 +1,302 @@
+use crate::auth;
+use crate::errors::{ApiError, ErrorCode};
+use crate::outbox::Outbox;
+use crate::polling::LpSession;
+use crate::presence::{LeaveReason, Transport};
+use crate::rooms::{Room, SseEvent};
+use crate::session;
 use crate::validator::extractor::ValidatedJson;
 use crate::validator::message::IncomingMessage;
-use crate::ws::broadcast::BroadcastServer;
-use crate::{AppState, ClientMessage};
+use crate::ws::broadcast::{self, BroadcastServer, Submit};
+use crate::{AppState, ClientMessage, Subscriber};
 use actix::Addr;
-use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, Error, HttpRequest, HttpResponse, Responder, ResponseError};
 use actix_web_lab::sse::{Data, Event, Sse};
 use futures_util::stream::StreamExt;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{mpsc, oneshot};
-use tokio::time::timeout;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 
 // --- SSE обработчик ---
-// pub async fn sse_handler(
-//     state: web::Data<AppState>,
-// ) -> Sse<impl futures_util::stream::Stream<Item = Result<Event, Error>>> {
-//     let sender_id = "sse_id".to_string();
-//     let (tx, rx) = mpsc::unbounded_channel::<String>();
-//     let mut senders = state.sse_senders.lock().await;
-//     senders.push((sender_id.clone(), tx));
-
-//     let event_stream =
-//         UnboundedReceiverStream::new(rx).map(|msg| Ok::<Event, Error>(Event::Data(Data::new(msg))));
+/// Снимает SSE‑подписчика с регистрации, когда его стрим уничтожен
+struct SseGuard {
+    room: Arc<Room>,
+    conn: u64,
+    outbox: Arc<Outbox<SseEvent>>,
+}
 
-//     Sse::from_stream(event_stream).with_keep_alive(std::time::Duration::from_secs(60))
-// }
+impl Drop for SseGuard {
+    fn drop(&mut self) {
+        let room = self.room.clone();
+        let conn = self.conn;
+        // очередь подписчика, который ещё зарегистрирован, сервер закрывает
+        // только из‑за медленного клиента
+        let reason = if self.outbox.stats().closed {
+            LeaveReason::SlowConsumer
+        } else {
+            LeaveReason::Closed
+        };
+        actix::spawn(async move {
+            room.remove_sse(conn, reason).await;
+        });
+    }
+}
 
 pub async fn sse_handler(
     req: HttpRequest,
     state: web::Data<AppState>,
-) -> Sse<impl futures_util::stream::Stream<Item = Result<Event, Error>>> {
-    // 1) Извлекаем sender_id из query: /sse?id=123
+) -> Result<Sse<impl futures_util::stream::Stream<Item = Result<Event, Error>>>, Error> {
+    // 1) Комната, sender_id и роль — из токена доступа (?token= или
+    //    Authorization: Bearer) или из query: /sse?room=room_1&id=123&type=ученик&sign=...
     let query: HashMap<String, String> = form_urlencoded::parse(req.query_string().as_bytes())
         .into_owned()
         .collect();
-    let sender_id = query.get("id").cloned().unwrap_or_default();
+    let (room_state, sender_id, role) = auth::identify(&req, &query, &state.rooms).await?;
+    let room = room_state.id.clone();
+    let session = {
+        let config = room_state.config.read().await;
+        // Сессию выдаёт сервер; с ?resume=<token> подключение занимает прежнюю
+        match query.get("resume") {
+            Some(token) => Some(auth::verify_resume(
+                &config, &room, &sender_id, &role, token,
+            )?),
+            None => None,
+        }
+    };
+    let resumed = session.is_some();
+    let session = session.unwrap_or_else(session::new_session_id);
+
+    // Переподключение: браузер сам присылает Last-Event-ID, первый раз можно передать ?since=
+    let since = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| query.get("since").cloned())
+        .and_then(|v| v.parse::<u64>().ok());
 
-    // 2) Создаём канал и регистрируем в AppState
-    let (tx, rx) = mpsc::unbounded_channel::<String>();
+    // 2) Присутствие нового подключения, затем закрытие прежних подключений
+    // этой сессии (или пользователя — по duplicate_sessions)
+    room_state
+        .user_connected(&sender_id, &role, Transport::Sse)
+        .await;
+    room_state.take_over(&sender_id, &session).await;
+
+    // 3) Создаём очередь и регистрируем в комнате: первым идёт WELCOME.
+    // Под блокировкой истории: пропущенное встаёт в очередь раньше новых сообщений.
+    let capacity = room_state.config.read().await.subscriber_queue;
+    let outbox = Arc::new(Outbox::new(capacity));
+    let conn = session::next_connection();
+    let welcome = room_state
+        .welcome(&sender_id, &role, &session, resumed)
+        .await;
+    outbox.push_unbounded(SseEvent::from_message(&welcome));
     {
-        let mut senders = state.sse_senders.lock().await;
-        senders.push((sender_id.clone(), tx));
+        let history = room_state.history.lock().await;
+        if let Some(since) = since {
+            for missed in history
+                .since(since)
+                .filter(|m| m.is_for_user(&sender_id, &role))
+            {
+                outbox.push_unbounded(SseEvent::from_message(missed));
+            }
+        }
+        let mut senders = room_state.sse_senders.write().await;
+        senders.insert(Subscriber {
+            id: sender_id.clone(),
+            role: role.clone(),
+            session,
+            conn,
+            tx: outbox.clone(),
+        });
     }
 
-    // 3) Превращаем rx в SSE‑стрим
-    let event_stream =
-        UnboundedReceiverStream::new(rx).map(|msg| Ok::<Event, Error>(Event::Data(Data::new(msg))));
+    // 4) Превращаем очередь в SSE‑стрим, seq — в id события.
+    // Когда клиент уходит, стрим (и guard в нём) уничтожается — снимаем регистрацию.
+    let guard = SseGuard {
+        room: room_state,
+        conn,
+        outbox: outbox.clone(),
+    };
+    let event_stream = outbox.stream().map(move |event| {
+        let _ = &guard;
+        let data = match event.seq {
+            Some(seq) => Data::new(event.data).id(seq.to_string()),
+            None => Data::new(event.data),
+        };
+        Ok::<Event, Error>(Event::Data(data))
+    });
 
-    // 4) Возвращаем Sse с периодическим keep-alive
-    Sse::from_stream(event_stream).with_keep_alive(std::time::Duration::from_secs(60))
+    // 5) Возвращаем Sse с периодическим keep-alive
+    Ok(Sse::from_stream(event_stream).with_keep_alive(state.timeouts.sse_keep_alive))
 }
 
 // --- Long Polling обработчик ---
+/// Опрос LP‑сессии: `POST /lp?session=<id>&cursor=<seq>`.
+/// Возвращает пачку `{session, cursor, messages, dropped}` со всем, что накопилось
+/// после подтверждённого курсора; если ничего нет — ждёт до `lp_poll_timeout_ms`.
+/// Без `session` (или с истёкшей) создаётся новая сессия, её id приходит в ответе.
 pub async fn long_polling_handler(
+    req: HttpRequest,
     state: web::Data<AppState>,
     msg: ValidatedJson<IncomingMessage>,
 ) -> impl Responder {
-    // Сохраняем sender_id единожды
-    let sender_id = msg.0.sender.id.clone();
+    // id сессии и seq последнего полученного сообщения: /lp?session=...&cursor=42
+    let query: HashMap<String, String> = form_urlencoded::parse(req.query_string().as_bytes())
+        .into_owned()
+        .collect();
+    let cursor = query.get("cursor").and_then(|v| v.parse::<u64>().ok());
 
-    // Регистрируемся как подписчик и создаём oneshot‑канал
-    let (tx, rx) = oneshot::channel::<String>();
-    {
-        let mut lps = state.lp_senders.lock().await;
-        lps.push((sender_id.clone(), tx));
-    }
+    let sender_id = msg.0.sender.id.clone();
+    let role = msg.0.sender.sender_type.clone();
+    // комната уже проверена в ValidatedJson, но могла быть закрыта с тех пор
+    let Some(room) = state.rooms.get(&msg.0.room_id).await else {
+        return auth::unknown_room(&msg.0.room_id).error_response();
+    };
 
-    // Ждём чужого сообщения или таймаута
-    let result = match timeout(Duration::from_secs(30), rx).await {
-        // Успех — получаем payload
-        Ok(Ok(payload)) => {
-            // и сразу удаляем себя из подписчиков,
-            // чтобы не остаться двжды и не получить своё следующее
-            let mut lps = state.lp_senders.lock().await;
-            lps.retain(|(sid, _)| sid != &sender_id);
-            payload
-        }
-        // Таймаут — тоже чистим
-        _ => {
-            let mut lps = state.lp_senders.lock().await;
-            lps.retain(|(sid, _)| sid != &sender_id);
-            "heartbeat timeout".to_string()
+    // Сессия живёт между опросами и находится по выданному сервером id —
+    // только среди сессий этого же пользователя. Новая (первый опрос или
+    // сессия истекла) с курсором сразу получает пропущенное из истории —
+    // под блокировкой истории, чтобы не пропустить и не получить дважды.
+    let session = {
+        let history = room.history.lock().await;
+        let mut lps = room.lp_senders.write().await;
+        let existing = query.get("session").and_then(|id| {
+            lps.of_user(&sender_id)
+                .find(|sub| sub.session == *id)
+                .map(|sub| (sub.conn, sub.tx.clone()))
+        });
+        match existing {
+            Some((conn, session)) if session.is_closed() => {
+                // сессия закрыта (KICK): отдаём последнюю пачку и снимаем её с регистрации
+                lps.remove(conn);
+                drop(lps);
+                drop(history);
+                session.begin_poll(cursor);
+                let batch = session.wait_batch(Duration::ZERO).await;
+                session.end_poll();
+                return HttpResponse::Ok().json(batch);
+            }
+            Some((conn, session)) => {
+                lps.set_role(conn, &role);
+                session
+            }
+            None => {
+                let session = Arc::new(LpSession::new(session::new_session_id()));
+                if let Some(cursor) = cursor {
+                    for missed in history
+                        .since(cursor)
+                        .filter(|m| m.is_for_user(&sender_id, &role))
+                    {
+                        session.push(&missed.msg);
+                    }
+                }
+                lps.insert(Subscriber {
+                    id: sender_id.clone(),
+                    role: role.clone(),
+                    session: session.id.clone(),
+                    conn: session::next_connection(),
+                    tx: session.clone(),
+                });
+                session
+            }
         }
     };
+    room.user_polled(&sender_id, &role).await;
+
+    session.begin_poll(cursor);
+    let batch = session.wait_batch(state.timeouts.lp_poll).await;
+    session.end_poll();
 
-    HttpResponse::Ok().json(result)
+    HttpResponse::Ok().json(batch)
 }
 
-/// Приём собственных сообщений и их мгновенная рассылка
+/// Приём собственных сообщений и их мгновенная рассылка.
+/// С `msg_id` ответ ждёт обработки и возвращает ACK. Без него команда
+/// разбирается и права проверяются сразу (ошибка — со своим HTTP‑статусом),
+/// а рассылка идёт в фоне: ответ — пустой 200.
 pub async fn send_handler(
+    state: web::Data<AppState>,
     srv: web::Data<Addr<BroadcastServer>>,
     msg: ValidatedJson<IncomingMessage>,
 ) -> impl Responder {
-    let origin = msg.0.sender.id.clone();
-    srv.do_send(ClientMessage {
-        msg: msg.0.clone(),
-        origin_sender_id: origin,
+    let submit = Submit(ClientMessage {
+        origin_sender_id: msg.0.sender.id.clone(),
+        msg: msg.0,
     });
+    if submit.0.msg.msg_id.is_none() {
+        let Some(room) = state.rooms.get(&submit.0.msg.room_id).await else {
+            return auth::unknown_room(&submit.0.msg.room_id).error_response();
+        };
+        if let Err(e) = broadcast::check(&room, &submit.0.msg).await {
+            return e.error_response();
+        }
+        srv.do_send(submit);
+        return HttpResponse::Ok().finish();
+    }
+    match srv.send(submit).await {
+        Ok(ack) => HttpResponse::Ok().json(ack.msg),
+        Err(e) => ApiError::new(ErrorCode::Internal, e.to_string()).error_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoomConfig;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+
+    /// Опрос LP от user_2, с выданной ранее сессией или без неё
+    fn poll(session: Option<&str>) -> test::TestRequest {
+        let uri = match session {
+            Some(session) => format!("/lp?session={}", session),
+            None => "/lp".to_string(),
+        };
+        let sign = auth::sign("secret", "room_1", "user_2", "ученик");
+        test::TestRequest::post().uri(&uri).set_json(json!({
+            "room_id": "room_1",
+            "sender": {"id": "user_2", "type": "ученик", "sign": sign},
+        }))
+    }
 
-    HttpResponse::Ok().finish()
+    #[actix_web::test]
+    async fn poll_with_session_reuses_it() {
+        let state = web::Data::new(AppState::for_tests("lp"));
+        let config = RoomConfig::parse(
+            "room = room_1\n\
+             teacher = user_1\n\
+             authorised_students = user_2\n\
+             sign_key = secret\n",
+        )
+        .unwrap();
+        state.rooms.upsert(config).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .route("/lp", web::post().to(long_polling_handler)),
+        )
+        .await;
+
+        let first: Value = test::call_and_read_body_json(&app, poll(None).to_request()).await;
+        let session = first["session"].as_str().unwrap().to_string();
+        let second: Value =
+            test::call_and_read_body_json(&app, poll(Some(&session)).to_request()).await;
+        assert_eq!(second["session"], session.as_str());
+
+        let room = state.rooms.get("room_1").await.unwrap();
+        assert_eq!(room.lp_senders.read().await.of_user("user_2").count(), 1);
+        let joined = room
+            .history
+            .lock()
+            .await
+            .since(0)
+            .filter(|m| m.msg.msg_command.as_deref() == Some("USER_JOINED"))
+            .count();
+        assert_eq!(joined, 1);
+
+        // без session — новая сессия со своим id
+        let other: Value = test::call_and_read_body_json(&app, poll(None).to_request()).await;
+        assert_ne!(other["session"], session.as_str());
+    }
 }
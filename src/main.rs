@@ -1,5 +1,25 @@
+mod auth;
+mod codec;
+mod commands;
 mod config;
+mod control;
+mod cors;
+mod drag;
+mod errors;
+mod history;
 mod http;
+mod outbox;
+mod permissions;
+mod polling;
+mod presence;
+mod protocol;
+mod queues;
+mod rooms;
+mod scene;
+mod session;
+mod storage;
+mod subscribers;
+mod upstream;
 mod users_list;
 mod ws;
 mod validator {
@@ -8,55 +28,128 @@ mod validator {
 }
 
 use actix::prelude::*;
-use actix_cors::Cors;
-use actix_web::http::header;
 use actix_web::{web, App, HttpServer};
-use config::RoomConfig;
-use once_cell::sync::Lazy;
+use clap::Parser;
+use config::server::{AllowedOrigins, Cli, Liveness, ServerConfig, Timeouts};
+use rooms::watcher::RoomWatcher;
+use rooms::RoomRegistry;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::sync::{mpsc, oneshot};
+use storage::file::JsonlStorage;
+use storage::Storage;
 use ws::broadcast::{BroadcastServer, ClientMessage};
 use ws::route::ws_route;
 
-/// файл с конфигом
-static ROOM_CONFIG: Lazy<RoomConfig> =
-    Lazy::new(|| RoomConfig::load_from_file("files/example_room.room"));
+/// Подписчик любого транспорта: кто он (id и роль) и куда ему слать.
+/// Роль нужна, чтобы доставлять сообщения с `target.scope = "type"`.
+pub struct Subscriber<T> {
+    pub id: String,
+    pub role: String,
+    /// Сессия, выданная сервером; переживает переподключение по токену
+    pub session: String,
+    /// Номер подключения: по нему подписчик снимается с регистрации
+    /// (у продолженной сессии старое и новое подключения различаются)
+    pub conn: u64,
+    pub tx: T,
+}
 
-// // --- Сообщение от клиента ---
 // --- Состояние приложения ---
 struct AppState {
-    pub ws_subs: Arc<Mutex<Vec<(String, Recipient<ClientMessage>)>>>,
-    sse_senders: Arc<Mutex<Vec<(String, mpsc::UnboundedSender<String>)>>>,
-    /// Для каждого LP‑запроса: (sender_id, oneshot::Sender<String>)
-    pub lp_senders: Arc<Mutex<Vec<(String, oneshot::Sender<String>)>>>,
+    /// Комнаты по room_id, у каждой свои подписчики и сцена
+    pub rooms: RoomRegistry,
+    /// Интервалы и таймауты транспортов из конфига сервера
+    pub timeouts: Timeouts,
+    /// Проверка, что клиенты живы
+    pub liveness: Liveness,
+    /// Origin браузерных клиентов: CORS и открытие WS
+    pub origins: AllowedOrigins,
 }
 
 impl AppState {
-    fn new() -> Self {
+    fn new(
+        storage: Arc<dyn Storage>,
+        timeouts: Timeouts,
+        liveness: Liveness,
+        origins: AllowedOrigins,
+    ) -> Self {
         Self {
-            ws_subs: Arc::new(Mutex::new(Vec::new())),
-            sse_senders: Arc::new(Mutex::new(Vec::new())),
-            lp_senders: Arc::new(Mutex::new(Vec::new())),
+            rooms: RoomRegistry::new(storage),
+            timeouts,
+            liveness,
+            origins,
         }
     }
+
+    /// Состояние для тестов: журналы во временном каталоге `name`,
+    /// короткие таймауты, комнат нет (их добавляет тест)
+    #[cfg(test)]
+    fn for_tests(name: &str) -> Self {
+        use std::time::Duration;
+        let dir = std::env::temp_dir().join(format!("cubecast-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = JsonlStorage::new(dir.to_str().unwrap()).unwrap();
+        Self::new(
+            Arc::new(storage),
+            Timeouts {
+                lp_poll: Duration::from_millis(50),
+                sse_keep_alive: Duration::from_secs(1),
+                rooms_poll: Duration::from_secs(1),
+            },
+            Liveness {
+                ws_ping_interval: Duration::from_secs(1),
+                ws_pong_timeout: Duration::from_secs(2),
+                app_ping_interval: Duration::from_secs(1),
+                app_ping: Vec::new(),
+            },
+            AllowedOrigins::default(),
+        )
+    }
 }
 
 // --- main ---
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // **В этом месте CONFIG ещё не считывается, он будет лениво инициализирован при первом обращении.**
-    // Чтобы заставить прочитать и распарсить конфиг сразу, нужно логгировать:
-    println!(
-        "Loaded config: room={}, teacher={}, sign_key={}, authorised_students={:?}",
-        ROOM_CONFIG.room,
-        ROOM_CONFIG.teacher,
-        ROOM_CONFIG.sign_key,
-        &ROOM_CONFIG.authorised_students
-    );
+    // Конфиг сервера: флаги, переменные окружения и server.toml.
+    // Каталоги по‑прежнему можно передать аргументами: CubeCastRouter ./rooms ./data
+    let config = ServerConfig::load(Cli::parse()).unwrap_or_else(|e| {
+        eprintln!("Ошибка конфига сервера: {}", e);
+        std::process::exit(2);
+    });
+    let tls = config.tls_config().unwrap_or_else(|e| {
+        eprintln!("Ошибка TLS: {}", e);
+        std::process::exit(2);
+    });
+    let rooms_dir = config.rooms_dir.clone();
+    let data_dir = config.data_dir.clone();
+
+    // 1) Создаём AppState и оборачиваем в web::Data.
+    // Комнаты поднимают сохранённые историю и сцену при создании.
+    let storage = JsonlStorage::new(&data_dir).map_err(|e| {
+        eprintln!("Не удалось открыть каталог данных {}: {}", data_dir, e);
+        e
+    })?;
+    let state = web::Data::new(AppState::new(
+        Arc::new(storage),
+        config.timeouts,
+        config.liveness.clone(),
+        config.origins.clone(),
+    ));
+    if !config.origins.any && config.origins.origins.is_empty() {
+        println!("allowed_origins пуст: браузерные клиенты с других сайтов будут отклонены");
+    }
 
-    // 1) Создаём AppState и оборачиваем в web::Data
-    let state = web::Data::new(AppState::new());
+    // Первый проход — до старта сервера, дальше каталог опрашивается в фоне
+    let mut watcher = RoomWatcher::new(&rooms_dir);
+    watcher.poll(&state.rooms).await;
+    {
+        let state = state.clone();
+        actix::spawn(async move {
+            let mut interval = tokio::time::interval(state.timeouts.rooms_poll);
+            loop {
+                interval.tick().await;
+                watcher.poll(&state.rooms).await;
+            }
+        });
+    }
 
     // 2) Запускаем актор BroadcastServer, передавая ему AppState
     let srv = BroadcastServer::new(state.clone()).start();
@@ -65,40 +158,38 @@ async fn main() -> std::io::Result<()> {
     let srv_data = web::Data::new(srv.clone());
 
     // 4) Создание и запуск HttpServer
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
             .app_data(srv_data.clone())
-            .wrap(
-                Cors::default()
-                    // Разрешить любые источники (в dev‑режиме; в проде лучше ужесточить)
-                    .allow_any_origin()
-                    // Разрешаем нужные HTTP‑методы
-                    .allowed_methods(vec!["GET", "POST", "OPTIONS"])
-                    // Разрешаем заголовок Content-Type
-                    .allowed_header(header::CONTENT_TYPE)
-                    // Если нужны другие кастомные заголовки, добавь их здесь:
-                    // .allowed_header(header::AUTHORIZATION)
-                    // Не забудь ответить на preflight-запросы
-                    .supports_credentials() // если используешь куки/авторизацию
-                    // TTL для preflight (в секундах)
-                    .max_age(3600),
-            )
+            // Origin WS проверяет сам маршрут: CORS на открытие WS не действует
             .route("/ws", web::get().to(ws_route))
-            .route("/sse", web::get().to(http::sse_handler))
-            .route("/lp", web::post().to(http::long_polling_handler))
-            .route("/send", web::post().to(http::send_handler))
-            .route("/wathing_users", web::post().to(users_list::get_users_list))
-    })
-    .bind(("127.0.0.1", 7070))?
-    .run()
-    .await
-}
-
-// и чтобы запрос GetWsClients возвращал Vec<String> с ID активных WS-юзеров.
+            .service(
+                web::scope("")
+                    .wrap(cors::cors(&state.origins))
+                    .route("/login", web::post().to(auth::login::login))
+                    .route("/sse", web::get().to(http::sse_handler))
+                    .route("/lp", web::post().to(http::long_polling_handler))
+                    .route("/send", web::post().to(http::send_handler))
+                    .route("/wathing_users", web::post().to(users_list::get_users_list))
+                    .route("/queues", web::post().to(queues::get_queues))
+                    .route("/protocol", web::get().to(protocol::get_protocol)),
+            )
+    });
 
-//todo проверить что сообщения не рассылаются самому себе по SSE  (LP WS  вроде сделано)
-//todo для sse_handler нет POST body данных для аутентификации
-//todo аутентификация (может быть и подпись signed сообщений)
-//todo  отправляемые команды нужно по всем каналам валидировать тоже (какие бывают) и исполнять (какой-то паттерн)
-//todo накопление сообщений
+    let addr = (config.host.as_str(), config.port);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let server = match tls {
+        Some(tls) => server.bind_rustls_0_23(addr, tls),
+        None => server.bind(addr),
+    }
+    .map_err(|e| {
+        eprintln!("Не удалось занять {}:{}: {}", config.host, config.port, e);
+        e
+    })?;
+    println!(
+        "Сервер слушает {}://{}:{}",
+        scheme, config.host, config.port
+    );
+    server.run().await
+}
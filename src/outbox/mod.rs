@@ -0,0 +1,320 @@
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Что делать, когда очередь подписчика заполнена (медленный клиент)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Вытеснить самое старое сообщение
+    #[default]
+    DropOldest,
+    /// Заменить ждущее обновление того же объекта (MOVE_CUBE, SELECT_CUBE),
+    /// а если такого нет — вытеснить самое старое
+    Coalesce,
+    /// Закрыть подключение с причиной
+    Disconnect,
+}
+
+impl SlowConsumerPolicy {
+    pub fn parse(val: &str) -> Result<Self, String> {
+        match val {
+            "drop_oldest" => Ok(SlowConsumerPolicy::DropOldest),
+            "coalesce" => Ok(SlowConsumerPolicy::Coalesce),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            other => Err(format!(
+                "Unknown slow_consumer `{}` (drop_oldest | coalesce | disconnect)",
+                other
+            )),
+        }
+    }
+}
+
+/// Элемент очереди: обновления с одинаковым ключом (команда, id объекта)
+/// можно схлопнуть
+pub trait Coalesce {
+    fn coalesce_key(&self) -> Option<(&str, &str)>;
+}
+
+/// Чем закончилась постановка в очередь
+#[derive(Debug, PartialEq)]
+pub enum Pushed {
+    Queued,
+    /// Вытеснено самое старое сообщение
+    Dropped,
+    /// Заменено ждущее обновление того же объекта
+    Coalesced,
+    /// Очередь полна, а политика — отключать; сообщение не поставлено
+    Overflow,
+    /// Очередь уже закрыта
+    Closed,
+}
+
+/// Счётчики очереди для операторов
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct OutboxStats {
+    pub depth: usize,
+    pub capacity: usize,
+    /// Максимальная глубина за всё время
+    pub high_water: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+    /// Очередь закрыта — подключение отключается
+    pub closed: bool,
+}
+
+struct Inner<T> {
+    queue: VecDeque<T>,
+    closed: bool,
+    stats: OutboxStats,
+}
+
+/// Ограниченная очередь исходящих сообщений одного подписчика.
+/// Сервер кладёт, подключение забирает через `stream()`;
+/// медленный клиент не может раздуть память сервера.
+pub struct Outbox<T> {
+    inner: Mutex<Inner<T>>,
+    notify: Notify,
+}
+
+impl<T> Outbox<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                queue: VecDeque::new(),
+                closed: false,
+                stats: OutboxStats {
+                    capacity,
+                    ..OutboxStats::default()
+                },
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Ставит сообщение в очередь по политике для переполнения
+    pub fn push(&self, item: T, policy: SlowConsumerPolicy) -> Pushed
+    where
+        T: Coalesce,
+    {
+        let pushed = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed {
+                return Pushed::Closed;
+            }
+            let mut pushed = Pushed::Queued;
+            if inner.queue.len() >= inner.stats.capacity {
+                let same = match (policy, item.coalesce_key()) {
+                    (SlowConsumerPolicy::Coalesce, Some(key)) => inner
+                        .queue
+                        .iter()
+                        .position(|queued| queued.coalesce_key() == Some(key)),
+                    _ => None,
+                };
+                match (policy, same) {
+                    (SlowConsumerPolicy::Disconnect, _) => return Pushed::Overflow,
+                    // старое обновление убираем, новое встаёт в конец — порядок seq сохраняется
+                    (_, Some(pos)) => {
+                        inner.queue.remove(pos);
+                        inner.stats.coalesced += 1;
+                        pushed = Pushed::Coalesced;
+                    }
+                    (_, None) => {
+                        inner.queue.pop_front();
+                        inner.stats.dropped += 1;
+                        pushed = Pushed::Dropped;
+                    }
+                }
+            }
+            inner.queue.push_back(item);
+            inner.stats.high_water = inner.stats.high_water.max(inner.queue.len());
+            pushed
+        };
+        self.notify.notify_one();
+        pushed
+    }
+
+    /// Кладёт без ограничения — для WELCOME и повторной доставки при подключении
+    /// (их объём и так ограничен историей комнаты)
+    pub fn push_unbounded(&self, item: T) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed {
+                return;
+            }
+            inner.queue.push_back(item);
+            inner.stats.high_water = inner.stats.high_water.max(inner.queue.len());
+        }
+        self.notify.notify_one();
+    }
+
+    /// Кладёт, только если очередь пуста (heartbeat не должен вытеснять данные)
+    pub fn push_if_idle(&self, item: T) -> bool {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed || !inner.queue.is_empty() {
+                return !inner.closed;
+            }
+            inner.queue.push_back(item);
+        }
+        self.notify.notify_one();
+        true
+    }
+
+    /// Закрывает очередь: то, что уже в ней, доставляется, за ним последним
+    /// уходит `last` (если есть), после чего поток завершается.
+    pub fn close(&self, last: Option<T>) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed {
+                return;
+            }
+            inner.queue.extend(last);
+            inner.closed = true;
+        }
+        self.notify.notify_one();
+    }
+
+    /// Закрывает очередь медленного клиента (политика `disconnect`):
+    /// недоставленное выбрасывается, уходит только `last`.
+    pub fn abort(&self, last: Option<T>) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.closed {
+                return;
+            }
+            inner.queue.clear();
+            inner.queue.extend(last);
+            inner.closed = true;
+        }
+        self.notify.notify_one();
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        let inner = self.inner.lock().unwrap();
+        OutboxStats {
+            depth: inner.queue.len(),
+            closed: inner.closed,
+            ..inner.stats.clone()
+        }
+    }
+
+    async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut inner = self.inner.lock().unwrap();
+                if let Some(item) = inner.queue.pop_front() {
+                    return Some(item);
+                }
+                if inner.closed {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Поток сообщений для подключения; завершается после `close`
+    pub fn stream(self: Arc<Self>) -> impl Stream<Item = T> {
+        stream::unfold(self, |outbox| async move {
+            let item = outbox.pop().await?;
+            Some((item, outbox))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    /// (команда, id объекта, номер) — номер только чтобы различать сообщения
+    #[derive(Debug, PartialEq)]
+    struct Item(&'static str, &'static str, u32);
+
+    impl Coalesce for Item {
+        fn coalesce_key(&self) -> Option<(&str, &str)> {
+            (self.0 == "MOVE_CUBE").then_some((self.0, self.1))
+        }
+    }
+
+    fn full(policy: SlowConsumerPolicy) -> Arc<Outbox<Item>> {
+        let outbox = Arc::new(Outbox::new(3));
+        assert_eq!(
+            outbox.push(Item("ADD_CUBE", "a", 1), policy),
+            Pushed::Queued
+        );
+        assert_eq!(
+            outbox.push(Item("MOVE_CUBE", "a", 2), policy),
+            Pushed::Queued
+        );
+        assert_eq!(
+            outbox.push(Item("MOVE_CUBE", "b", 3), policy),
+            Pushed::Queued
+        );
+        outbox
+    }
+
+    async fn drain(outbox: Arc<Outbox<Item>>) -> Vec<u32> {
+        outbox.close(None);
+        outbox.stream().map(|item| item.2).collect().await
+    }
+
+    #[actix_web::test]
+    async fn drop_oldest_evicts_the_head() {
+        let outbox = full(SlowConsumerPolicy::DropOldest);
+        let pushed = outbox.push(Item("MOVE_CUBE", "a", 4), SlowConsumerPolicy::DropOldest);
+        assert_eq!(pushed, Pushed::Dropped);
+        let stats = outbox.stats();
+        assert_eq!((stats.depth, stats.dropped, stats.high_water), (3, 1, 3));
+        assert_eq!(drain(outbox).await, vec![2, 3, 4]);
+    }
+
+    #[actix_web::test]
+    async fn coalesce_replaces_the_pending_update_of_the_same_object() {
+        let policy = SlowConsumerPolicy::Coalesce;
+        let outbox = full(policy);
+        assert_eq!(
+            outbox.push(Item("MOVE_CUBE", "a", 4), policy),
+            Pushed::Coalesced
+        );
+        // схлопнуть нечего — вытесняется самое старое
+        assert_eq!(
+            outbox.push(Item("ADD_CUBE", "c", 5), policy),
+            Pushed::Dropped
+        );
+        let stats = outbox.stats();
+        assert_eq!((stats.coalesced, stats.dropped), (1, 1));
+        assert_eq!(drain(outbox).await, vec![3, 4, 5]);
+    }
+
+    #[actix_web::test]
+    async fn disconnect_refuses_and_abort_keeps_only_the_last_message() {
+        let policy = SlowConsumerPolicy::Disconnect;
+        let outbox = full(policy);
+        assert_eq!(
+            outbox.push(Item("MOVE_CUBE", "a", 4), policy),
+            Pushed::Overflow
+        );
+        outbox.abort(Some(Item("SESSION_CLOSED", "", 9)));
+        assert!(outbox.stats().closed);
+        assert_eq!(
+            outbox.push(Item("ADD_CUBE", "c", 5), policy),
+            Pushed::Closed
+        );
+        let items: Vec<u32> = outbox.stream().map(|item| item.2).collect().await;
+        assert_eq!(items, vec![9]);
+    }
+
+    #[actix_web::test]
+    async fn close_delivers_pending_messages_before_the_last_one() {
+        let outbox = full(SlowConsumerPolicy::DropOldest);
+        outbox.close(Some(Item("SESSION_CLOSED", "", 9)));
+        // повторное закрытие ничего не добавляет
+        outbox.close(Some(Item("SESSION_CLOSED", "", 10)));
+        let items: Vec<u32> = outbox.stream().map(|item| item.2).collect().await;
+        assert_eq!(items, vec![1, 2, 3, 9]);
+    }
+}
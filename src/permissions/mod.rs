@@ -0,0 +1,58 @@
+/// Права одной роли. Какие команды роль может отправлять,
+/// задаёт реестр команд (`commands::COMMANDS`).
+pub struct Permissions {
+    pub role: &'static str,
+    /// Можно ли слать сообщения без команды (обычная рассылка)
+    pub plain: bool,
+    /// Разрешённые target.scope (сообщение без target считается "all")
+    pub scopes: &'static [&'static str],
+}
+
+/// Все значения target.scope
+pub const ALL_SCOPES: &[&str] = &["all", "type", "ids"];
+
+/// Таблица прав по ролям.
+/// "heartbeat" здесь нет: это роль сервера, клиенту она запрещена.
+pub static PERMISSIONS: &[Permissions] = &[
+    // проводит занятие — все права
+    Permissions {
+        role: "учитель",
+        plain: true,
+        scopes: ALL_SCOPES,
+    },
+    // невидимый, но со всеми правами
+    Permissions {
+        role: "ADMIN",
+        plain: true,
+        scopes: ALL_SCOPES,
+    },
+    // участник: пишет всем или адресно
+    Permissions {
+        role: "ученик",
+        plain: true,
+        scopes: &["all", "ids"],
+    },
+    // наблюдает втайне: в комнату ничего не рассылает
+    Permissions {
+        role: "наблюдатель",
+        plain: false,
+        scopes: &[],
+    },
+];
+
+/// Роли, которых не видно в списке пользователей и в событиях присутствия
+pub const HIDDEN_ROLES: &[&str] = &["наблюдатель", "ADMIN"];
+/// Роли, которые видят скрытых
+pub const PRIVILEGED_ROLES: &[&str] = &["учитель", "ADMIN"];
+
+pub fn is_hidden(role: &str) -> bool {
+    HIDDEN_ROLES.contains(&role)
+}
+
+pub fn sees_hidden(role: &str) -> bool {
+    PRIVILEGED_ROLES.contains(&role)
+}
+
+pub fn for_role(role: &str) -> Option<&'static Permissions> {
+    PERMISSIONS.iter().find(|p| p.role == role)
+}
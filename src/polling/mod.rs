@@ -0,0 +1,202 @@
+use crate::validator::message::IncomingMessage;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Сколько сообщений копится для LP‑клиента между опросами;
+/// при переполнении вытесняются самые старые
+const LP_QUEUE_CAPACITY: usize = 100;
+
+/// Ответ на опрос: всё, что накопилось после подтверждённого курсора
+#[derive(Serialize)]
+pub struct LpBatch {
+    /// id сессии — клиент передаёт его в следующий опрос как `session`
+    pub session: String,
+    /// seq последнего отданного сообщения — его клиент передаёт как `cursor`
+    pub cursor: Option<u64>,
+    pub messages: Vec<IncomingMessage>,
+    /// Сколько сообщений вытеснено из переполненной очереди с прошлого ответа
+    pub dropped: usize,
+}
+
+struct LpQueue {
+    /// Сообщения и отданы ли они уже клиентом в ответе на опрос
+    messages: VecDeque<(IncomingMessage, bool)>,
+    dropped: usize,
+    /// seq последнего отданного сообщения (курсор по умолчанию)
+    last_sent: Option<u64>,
+    /// Сколько опросов сейчас ждут; пока ждут — сессия не простаивает
+    polling: usize,
+    last_poll: Instant,
+    /// Сессия закрыта (KICK): новых сообщений не принимает, ждёт, пока
+    /// клиент заберёт последнюю пачку
+    closed: bool,
+}
+
+/// Серверная сессия LP‑клиента: очередь адресованных ему сообщений
+/// живёт между опросами. Сообщение удаляется из очереди только после
+/// подтверждения курсором, поэтому оборванный ответ не теряет данных.
+pub struct LpSession {
+    /// Выдаётся сервером при создании сессии
+    pub id: String,
+    queue: Mutex<LpQueue>,
+    notify: Notify,
+}
+
+impl LpSession {
+    pub fn new(id: String) -> Self {
+        Self {
+            id,
+            queue: Mutex::new(LpQueue {
+                messages: VecDeque::new(),
+                dropped: 0,
+                last_sent: None,
+                polling: 0,
+                last_poll: Instant::now(),
+                closed: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Кладёт сообщение в очередь и будит ждущий опрос
+    pub fn push(&self, msg: &IncomingMessage) {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.closed {
+                return;
+            }
+            if queue.messages.len() == LP_QUEUE_CAPACITY {
+                queue.messages.pop_front();
+                queue.dropped += 1;
+            }
+            queue.messages.push_back((msg.clone(), false));
+        }
+        self.notify.notify_one();
+    }
+
+    /// Закрывает сессию: `msg` (SESSION_CLOSED) становится последним
+    /// сообщением, ждущий опрос сразу получает ответ
+    pub fn close(&self, msg: &IncomingMessage) {
+        self.push(msg);
+        self.queue.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queue.lock().unwrap().closed
+    }
+
+    /// Начало опроса: подтверждает всё до `cursor` включительно
+    /// (без курсора — всё, что отдано в прошлый раз). Ответы без seq
+    /// (ERROR, STATE) подтверждаются, только если уже были отданы.
+    pub fn begin_poll(&self, cursor: Option<u64>) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.polling += 1;
+        queue.last_poll = Instant::now();
+        if let Some(acked) = cursor.or(queue.last_sent) {
+            while queue
+                .messages
+                .front()
+                .is_some_and(|(m, sent)| m.seq.map_or(*sent, |seq| seq <= acked))
+            {
+                queue.messages.pop_front();
+            }
+        }
+    }
+
+    pub fn end_poll(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.polling = queue.polling.saturating_sub(1);
+        queue.last_poll = Instant::now();
+    }
+
+    /// Ждёт сообщений до `timeout`; пустая пачка — за это время ничего не пришло.
+    /// Отданные сообщения остаются в очереди до подтверждения.
+    /// Закрытая сессия отвечает сразу.
+    pub async fn wait_batch(&self, timeout: Duration) -> LpBatch {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if !queue.messages.is_empty()
+                    || queue.closed
+                    || tokio::time::Instant::now() >= deadline
+                {
+                    let messages: Vec<IncomingMessage> = queue
+                        .messages
+                        .iter_mut()
+                        .map(|(m, sent)| {
+                            *sent = true;
+                            m.clone()
+                        })
+                        .collect();
+                    if let Some(seq) = messages.iter().rev().find_map(|m| m.seq) {
+                        queue.last_sent = Some(seq);
+                    }
+                    return LpBatch {
+                        session: self.id.clone(),
+                        cursor: queue.last_sent,
+                        messages,
+                        dropped: std::mem::take(&mut queue.dropped),
+                    };
+                }
+            }
+            // notify_one оставляет разрешение, если сообщение пришло раньше ожидания
+            let _ = tokio::time::timeout_at(deadline, self.notify.notified()).await;
+        }
+    }
+
+    /// Сколько сессия простаивает; `None` — клиент сейчас ждёт ответа
+    pub fn idle_for(&self) -> Option<Duration> {
+        let queue = self.queue.lock().unwrap();
+        (queue.polling == 0).then(|| queue.last_poll.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(seq: Option<u64>) -> IncomingMessage {
+        serde_json::from_value(serde_json::json!({
+            "room_id": "room_1",
+            "sender": {"id": "server", "type": "server"},
+            "seq": seq,
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn reply_after_batch_survives_cursor() {
+        let session = LpSession::new("s1".to_string());
+        session.push(&message(Some(5)));
+        session.begin_poll(None);
+        let batch = session.wait_batch(Duration::ZERO).await;
+        session.end_poll();
+        assert_eq!(batch.cursor, Some(5));
+
+        // ERROR без seq пришёл после ответа: подтверждение курсором 5 его не снимает
+        session.push(&message(None));
+        session.begin_poll(Some(5));
+        let batch = session.wait_batch(Duration::ZERO).await;
+        assert_eq!(batch.messages.len(), 1);
+        assert_eq!(batch.messages[0].seq, None);
+    }
+
+    #[actix_web::test]
+    async fn closed_session_ends_with_its_last_message() {
+        let session = LpSession::new("s1".to_string());
+        session.push(&message(Some(1)));
+        session.close(&message(None));
+        session.push(&message(Some(2)));
+        assert!(session.is_closed());
+
+        session.begin_poll(None);
+        let batch = session.wait_batch(Duration::from_secs(60)).await;
+        let seqs: Vec<_> = batch.messages.iter().map(|m| m.seq).collect();
+        assert_eq!(seqs, vec![Some(1), None]);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::permissions;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Транспорт, через который подключён пользователь
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub enum Transport {
+    #[serde(rename = "ws")]
+    Ws,
+    #[serde(rename = "sse")]
+    Sse,
+    #[serde(rename = "long_polling")]
+    LongPolling,
+}
+
+impl Transport {
+    pub fn parse(val: &str) -> Result<Self, String> {
+        match val {
+            "ws" => Ok(Transport::Ws),
+            "sse" => Ok(Transport::Sse),
+            "long_polling" => Ok(Transport::LongPolling),
+            other => Err(format!(
+                "Unknown transport `{}` (ws | sse | long_polling)",
+                other
+            )),
+        }
+    }
+}
+
+/// Почему пользователь ушёл с транспорта — приходит в USER_LEFT
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaveReason {
+    /// Клиент закрыл соединение (или оно оборвалось)
+    Closed,
+    /// WS‑клиент не отвечал дольше ws_pong_timeout_ms
+    Timeout,
+    /// Клиент не успевал получать сообщения
+    SlowConsumer,
+    /// Сессию закрыл сервер (продолжена в другом подключении, повторный вход)
+    SessionClosed,
+    /// LP‑клиент перестал опрашивать
+    Idle,
+    /// Учитель удалил пользователя из комнаты (KICK)
+    Kicked,
+    /// Новый конфиг комнаты больше не допускает пользователя
+    Revoked,
+}
+
+/// Присутствие пользователя на одном транспорте
+struct UserPresence {
+    role: String,
+    /// Сколько открытых соединений (несколько вкладок); для LP всегда 1
+    connections: usize,
+    connected_since: SystemTime,
+    /// Когда пользователь последний раз что-то отправлял
+    last_activity: SystemTime,
+    /// Когда последний раз был виден (для LP — последний опрос)
+    last_seen: SystemTime,
+}
+
+/// Пользователь в ответе на запрос списка
+#[derive(Clone, Debug, Serialize)]
+pub struct UserInfo {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub role: String, // "учитель" | "ученик" | "наблюдатель" | "ADMIN"
+    pub connection: Transport,
+    /// Unix‑время в мс
+    pub connected_since: u64,
+    pub last_activity: u64,
+}
+
+/// Кто сейчас в комнате и через какие транспорты
+#[derive(Default)]
+pub struct Presence {
+    users: HashMap<(String, Transport), UserPresence>,
+}
+
+fn unix_ms(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Presence {
+    /// Новое соединение. `true` — пользователь на этом транспорте появился впервые.
+    pub fn connect(&mut self, id: &str, role: &str, transport: Transport) -> bool {
+        let now = SystemTime::now();
+        match self.users.get_mut(&(id.to_string(), transport)) {
+            Some(user) => {
+                user.connections += 1;
+                user.last_seen = now;
+                false
+            }
+            None => {
+                self.users.insert(
+                    (id.to_string(), transport),
+                    UserPresence {
+                        role: role.to_string(),
+                        connections: 1,
+                        connected_since: now,
+                        last_activity: now,
+                        last_seen: now,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Соединение закрыто. Возвращает роль, если это было последнее соединение
+    /// пользователя на этом транспорте (т.е. он ушёл).
+    pub fn disconnect(&mut self, id: &str, transport: Transport) -> Option<String> {
+        let key = (id.to_string(), transport);
+        let user = self.users.get_mut(&key)?;
+        user.connections = user.connections.saturating_sub(1);
+        if user.connections > 0 {
+            return None;
+        }
+        self.users.remove(&key).map(|user| user.role)
+    }
+
+    /// Опрос LP: соединения как такового нет, пользователь «жив», пока опрашивает.
+    /// `true` — пользователь появился впервые.
+    pub fn poll(&mut self, id: &str, role: &str) -> bool {
+        match self
+            .users
+            .get_mut(&(id.to_string(), Transport::LongPolling))
+        {
+            Some(user) => {
+                user.last_seen = SystemTime::now();
+                false
+            }
+            None => self.connect(id, role, Transport::LongPolling),
+        }
+    }
+
+    /// Убирает LP‑пользователей, которые не опрашивали дольше `max_idle`.
+    /// Возвращает (id, роль) ушедших.
+    pub fn prune_idle_polls(&mut self, max_idle: Duration) -> Vec<(String, String)> {
+        let now = SystemTime::now();
+        let stale: Vec<String> = self
+            .users
+            .iter()
+            .filter(|((_, transport), user)| {
+                *transport == Transport::LongPolling
+                    && now.duration_since(user.last_seen).unwrap_or_default() > max_idle
+            })
+            .map(|((id, _), _)| id.clone())
+            .collect();
+        stale
+            .into_iter()
+            .filter_map(|id| {
+                self.users
+                    .remove(&(id.clone(), Transport::LongPolling))
+                    .map(|user| (id, user.role))
+            })
+            .collect()
+    }
+
+    /// Есть ли пользователь в комнате хоть на одном транспорте
+    pub fn contains(&self, id: &str) -> bool {
+        self.users.keys().any(|(uid, _)| uid == id)
+    }
+
+    /// Роли, с которыми пользователь сейчас в комнате
+    pub fn roles(&self, id: &str) -> Vec<String> {
+        self.users
+            .iter()
+            .filter(|((uid, _), _)| uid == id)
+            .map(|(_, user)| user.role.clone())
+            .collect()
+    }
+
+    /// Пользователь что-то отправил
+    pub fn touch(&mut self, id: &str) {
+        let now = SystemTime::now();
+        for ((uid, _), user) in self.users.iter_mut() {
+            if uid == id {
+                user.last_activity = now;
+                user.last_seen = now;
+            }
+        }
+    }
+
+    /// Список пользователей; скрытые роли — только если `include_hidden`
+    pub fn list(&self, include_hidden: bool) -> Vec<UserInfo> {
+        let mut users: Vec<UserInfo> = self
+            .users
+            .iter()
+            .filter(|(_, user)| include_hidden || !permissions::is_hidden(&user.role))
+            .map(|((id, transport), user)| UserInfo {
+                id: id.clone(),
+                role: user.role.clone(),
+                connection: *transport,
+                connected_since: unix_ms(user.connected_since),
+                last_activity: unix_ms(user.last_activity),
+            })
+            .collect();
+        users.sort_by(|a, b| {
+            a.connected_since
+                .cmp(&b.connected_since)
+                .then(a.id.cmp(&b.id))
+        });
+        users
+    }
+}
@@ -0,0 +1,98 @@
+use crate::commands::COMMANDS;
+use crate::permissions::{ALL_SCOPES, PERMISSIONS};
+use crate::validator::message::IncomingMessage;
+use actix_web::{HttpResponse, Responder};
+use once_cell::sync::Lazy;
+use schemars::{schema_for, Schema};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Команда реестра и схема её payload
+#[derive(Serialize)]
+struct CommandInfo {
+    name: &'static str,
+    roles: &'static [&'static str],
+    mutates: bool,
+    to_server: bool,
+    payload: Option<Schema>,
+}
+
+/// Права роли из `permissions::PERMISSIONS`
+#[derive(Serialize)]
+struct RoleInfo {
+    role: &'static str,
+    plain: bool,
+    scopes: &'static [&'static str],
+}
+
+#[derive(Serialize)]
+struct Protocol {
+    envelope: Schema,
+    commands: Vec<CommandInfo>,
+    roles: Vec<RoleInfo>,
+    target_scopes: &'static [&'static str],
+}
+
+/// Описание протокола строится из реестра команд и таблицы прав один раз
+static PROTOCOL: Lazy<Value> = Lazy::new(|| {
+    let protocol = Protocol {
+        envelope: schema_for!(IncomingMessage),
+        commands: COMMANDS
+            .iter()
+            .map(|c| CommandInfo {
+                name: c.name,
+                roles: c.roles,
+                mutates: c.mutates,
+                to_server: c.to_server,
+                payload: (c.payload)(),
+            })
+            .collect(),
+        roles: PERMISSIONS
+            .iter()
+            .map(|p| RoleInfo {
+                role: p.role,
+                plain: p.plain,
+                scopes: p.scopes,
+            })
+            .collect(),
+        target_scopes: ALL_SCOPES,
+    };
+    serde_json::to_value(protocol).unwrap_or(Value::Null)
+});
+
+/// GET /protocol: JSON Schema конверта `IncomingMessage` и payload каждой
+/// команды реестра, роли и допустимые target.scope — для валидации и
+/// генерации кода на клиентах.
+pub async fn get_protocol() -> impl Responder {
+    HttpResponse::Ok().json(&*PROTOCOL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_lists_every_registered_command() {
+        let commands = PROTOCOL["commands"].as_array().unwrap();
+        let names: Vec<&str> = commands
+            .iter()
+            .map(|c| c["name"].as_str().unwrap())
+            .collect();
+        let registered: Vec<&str> = COMMANDS.iter().map(|c| c.name).collect();
+        assert_eq!(names, registered);
+        for (info, spec) in commands.iter().zip(COMMANDS) {
+            // у команды с payload — его схема, без payload — null
+            assert_eq!(
+                info["payload"].is_object(),
+                (spec.payload)().is_some(),
+                "{}",
+                spec.name
+            );
+        }
+        assert!(PROTOCOL["envelope"]["properties"]["sender"].is_object());
+        assert_eq!(
+            PROTOCOL["roles"].as_array().unwrap().len(),
+            PERMISSIONS.len()
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use crate::auth;
+use crate::errors::{ApiError, ErrorCode};
+use crate::outbox::OutboxStats;
+use crate::permissions;
+use crate::validator::extractor::ValidatedJson;
+use crate::validator::message::IncomingMessage;
+use crate::AppState;
+use actix_web::{web, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+
+/// Очередь одного подключения (WS или SSE)
+#[derive(Serialize)]
+struct QueueInfo {
+    id: String,
+    #[serde(rename = "type")]
+    role: String,
+    connection: &'static str,
+    session: String,
+    #[serde(flatten)]
+    stats: OutboxStats,
+}
+
+#[derive(Serialize)]
+struct QueuesReport {
+    room_id: String,
+    queues: Vec<QueueInfo>,
+}
+
+/// POST /queues: глубина и счётчики очередей подписчиков комнаты.
+/// Для операторов — доступно только ролям, которые видят скрытых участников.
+pub async fn get_queues(
+    state: web::Data<AppState>,
+    msg: ValidatedJson<IncomingMessage>,
+) -> impl Responder {
+    if !permissions::sees_hidden(&msg.0.sender.sender_type) {
+        return ApiError::new(
+            ErrorCode::Forbidden,
+            "Очереди подписчиков доступны только учителю и ADMIN",
+        )
+        .field("sender.type")
+        .error_response();
+    }
+    let Some(room) = state.rooms.get(&msg.0.room_id).await else {
+        return auth::unknown_room(&msg.0.room_id).error_response();
+    };
+
+    let mut queues = Vec::new();
+    for sub in room.ws_subs.read().await.iter() {
+        queues.push(QueueInfo {
+            id: sub.id.clone(),
+            role: sub.role.clone(),
+            connection: "ws",
+            session: sub.session.clone(),
+            stats: sub.tx.outbox.stats(),
+        });
+    }
+    for sub in room.sse_senders.read().await.iter() {
+        queues.push(QueueInfo {
+            id: sub.id.clone(),
+            role: sub.role.clone(),
+            connection: "sse",
+            session: sub.session.clone(),
+            stats: sub.tx.stats(),
+        });
+    }
+    // самые загруженные — первыми
+    queues.sort_by_key(|q| std::cmp::Reverse(q.stats.depth));
+
+    HttpResponse::Ok().json(QueuesReport {
+        room_id: msg.0.room_id.clone(),
+        queues,
+    })
+}
@@ -0,0 +1,563 @@
+pub mod watcher;
+
+use crate::config::RoomConfig;
+use crate::control::{Control, ControlCommand};
+use crate::drag::DragUpdates;
+use crate::errors::ApiError;
+use crate::history::History;
+use crate::outbox::{Coalesce, Outbox};
+use crate::permissions;
+use crate::polling::LpSession;
+use crate::presence::{LeaveReason, Presence, Transport};
+use crate::scene::Scene;
+use crate::session::{DuplicatePolicy, Welcome};
+use crate::storage::{self, Record, Storage, Writer};
+use crate::subscribers::Subscribers;
+use crate::validator::message::Target;
+use crate::ws::broadcast::{deliver, WsClient};
+use crate::ws::Disconnect;
+use crate::ClientMessage;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::{Mutex, RwLock};
+
+/// Событие для SSE‑подписчика; `seq` уходит в поле id (для Last-Event-ID)
+#[derive(Clone)]
+pub struct SseEvent {
+    pub seq: Option<u64>,
+    pub data: String,
+    /// (команда, id объекта) — для схлопывания обновлений в очереди
+    pub key: Option<(String, String)>,
+}
+
+impl SseEvent {
+    pub fn from_message(msg: &ClientMessage) -> Self {
+        Self {
+            seq: msg.msg.seq,
+            data: serde_json::to_string(&msg.msg).unwrap(),
+            key: msg
+                .coalesce_key()
+                .map(|(command, id)| (command.to_string(), id.to_string())),
+        }
+    }
+}
+
+impl Coalesce for SseEvent {
+    fn coalesce_key(&self) -> Option<(&str, &str)> {
+        self.key.as_ref().map(|(c, id)| (c.as_str(), id.as_str()))
+    }
+}
+
+pub type WsSubs = RwLock<Subscribers<WsClient>>;
+pub type SseSubs = RwLock<Subscribers<Arc<Outbox<SseEvent>>>>;
+pub type LpSubs = RwLock<Subscribers<Arc<LpSession>>>;
+
+/// Одна комната (занятие): свой конфиг, свои подписчики и своя сцена.
+/// Сообщения никогда не выходят за пределы комнаты.
+pub struct Room {
+    pub id: String,
+    /// Конфиг может меняться на лету (см. watcher), id комнаты — нет
+    pub config: RwLock<RoomConfig>,
+    pub ws_subs: WsSubs,
+    pub sse_senders: SseSubs,
+    /// LP‑сессии: очередь сообщений каждого клиента между опросами
+    pub lp_senders: LpSubs,
+    pub scene: Mutex<Scene>,
+    /// Управление комнатой учителем: MUTE, блокировка сцены, передача управления
+    pub control: Mutex<Control>,
+    /// Перетаскивания, ждущие конца окна схлопывания (coalesce_window_ms)
+    pub drag: Mutex<DragUpdates>,
+    /// Кто в комнате: по нему строится список пользователей и события USER_JOINED/USER_LEFT
+    pub presence: Mutex<Presence>,
+    /// Последние разосланные сообщения для повторной доставки
+    pub history: Mutex<History>,
+    /// Куда сохраняются история и сцена
+    pub writer: Writer,
+}
+
+impl Room {
+    /// Новая комната; история и сцена восстанавливаются из записей хранилища
+    pub fn new(config: RoomConfig, records: Vec<Record>, writer: Writer) -> Self {
+        let (messages, scene) = storage::restore(records);
+        if !messages.is_empty() {
+            println!(
+                "Комната {}: восстановлено {} сообщений истории",
+                config.room,
+                messages.len()
+            );
+        }
+        let mut history = History::default();
+        history.restore(messages);
+
+        Self {
+            id: config.room.clone(),
+            config: RwLock::new(config),
+            ws_subs: RwLock::new(Subscribers::default()),
+            sse_senders: RwLock::new(Subscribers::default()),
+            lp_senders: RwLock::new(Subscribers::default()),
+            scene: Mutex::new(scene),
+            control: Mutex::new(Control::default()),
+            drag: Mutex::new(DragUpdates::default()),
+            presence: Mutex::new(Presence::default()),
+            history: Mutex::new(history),
+            writer,
+        }
+    }
+
+    /// Сжимает журнал комнаты в хранилище: остаётся история и снимок сцены.
+    /// Под блокировкой истории снимаются только копии, пишет их поток `Writer`
+    /// после всех уже поставленных в очередь сообщений.
+    pub async fn compact(&self) {
+        let history = self.history.lock().await;
+        let scene = self.scene.lock().await.clone();
+        let messages: Vec<ClientMessage> = history.since(0).cloned().collect();
+        self.writer.compact(&self.id, messages, scene);
+    }
+
+    /// Новое соединение; если пользователь появился впервые — USER_JOINED
+    pub async fn user_connected(&self, id: &str, role: &str, transport: Transport) {
+        let joined = self.presence.lock().await.connect(id, role, transport);
+        if joined {
+            self.announce("USER_JOINED", id, role, transport, None)
+                .await;
+        }
+    }
+
+    /// Соединение закрыто; если оно было последним — USER_LEFT с причиной
+    pub async fn user_disconnected(&self, id: &str, transport: Transport, reason: LeaveReason) {
+        println!(
+            "Комната {}: {} отключился ({:?}, {:?})",
+            self.id, id, transport, reason
+        );
+        let left = self.presence.lock().await.disconnect(id, transport);
+        if let Some(role) = left {
+            self.announce("USER_LEFT", id, &role, transport, Some(reason))
+                .await;
+            self.user_gone(id).await;
+        }
+    }
+
+    /// Пользователь ушёл с транспорта; если его больше нет в комнате,
+    /// переданное ему управление возвращается учителю
+    pub async fn user_gone(&self, id: &str) {
+        if self.presence.lock().await.contains(id) {
+            return;
+        }
+        let control = {
+            let mut control = self.control.lock().await;
+            if !control.controls(id) {
+                return;
+            }
+            control.controller = None;
+            control.clone()
+        };
+        let event = self.control_event(
+            &ControlCommand::PassControl { id: None },
+            "server",
+            &control,
+        );
+        deliver(self, event).await;
+    }
+
+    /// ROOM_CONTROL — изменение управления комнатой: что сделано, кем и
+    /// состояние после изменения. Приходит всем, включая того, кто изменил.
+    pub fn control_event(
+        &self,
+        cmd: &ControlCommand,
+        by: &str,
+        control: &Control,
+    ) -> ClientMessage {
+        ClientMessage::server(
+            &self.id,
+            None,
+            "ROOM_CONTROL",
+            serde_json::json!({
+                "action": cmd.name(),
+                "id": cmd.user(),
+                "by": by,
+                "control": control,
+            }),
+        )
+    }
+
+    /// KICK: закрывает подключения пользователя на всех транспортах.
+    /// WS и SSE получают SESSION_CLOSED и закрываются. LP‑сессия получает его
+    /// последним сообщением и остаётся зарегистрированной закрытой, пока клиент
+    /// не заберёт эту пачку (см. `admit`).
+    pub async fn kick(&self, id: &str) {
+        let reason = "Учитель удалил вас из комнаты";
+        self.close_sessions(reason, LeaveReason::Kicked, |uid, _, _| uid == id)
+            .await;
+        self.close_lp_sessions(reason, LeaveReason::Kicked, |uid, _| uid == id)
+            .await;
+    }
+
+    /// Новый конфиг комнаты (от watcher). Подключения тех, кто больше
+    /// не допущен в комнату с прежней ролью, закрываются; при смене sign_key —
+    /// все подключения: их подписи и токены больше не действительны.
+    pub async fn reconfigure(&self, config: RoomConfig) {
+        let rekeyed = {
+            let mut current = self.config.write().await;
+            let rekeyed = current.sign_key != config.sign_key;
+            *current = config.clone();
+            rekeyed
+        };
+        let revoked = |id: &str, role: &str| rekeyed || !config.role_allowed(id, role);
+        let reason = "Доступ в комнату отозван";
+        self.close_sessions(reason, LeaveReason::Revoked, |id, role, _| {
+            revoked(id, role)
+        })
+        .await;
+        self.close_lp_sessions(reason, LeaveReason::Revoked, revoked)
+            .await;
+    }
+
+    /// Пускает ли комната пользователя: удалённого (KICK) — нет, пока учитель
+    /// его не вернёт (UNKICK). Исключение — опрос его закрытой LP‑сессии,
+    /// которая ещё не отдала последнюю пачку с SESSION_CLOSED.
+    pub async fn admit(&self, id: &str) -> Result<(), ApiError> {
+        let admitted = self.control.lock().await.admit(id);
+        if admitted.is_err()
+            && self
+                .lp_senders
+                .read()
+                .await
+                .of_user(id)
+                .any(|sub| sub.tx.is_closed())
+        {
+            return Ok(());
+        }
+        admitted
+    }
+
+    /// LP‑опрос; если пользователь появился впервые — USER_JOINED
+    pub async fn user_polled(&self, id: &str, role: &str) {
+        let joined = self.presence.lock().await.poll(id, role);
+        if joined {
+            self.announce("USER_JOINED", id, role, Transport::LongPolling, None)
+                .await;
+        }
+    }
+
+    /// WELCOME для подключения: id сессии, токен продолжения и последний seq
+    pub async fn welcome(
+        &self,
+        id: &str,
+        role: &str,
+        session: &str,
+        resumed: bool,
+    ) -> ClientMessage {
+        let seq = self.history.lock().await.last_seq();
+        let mut welcome = Welcome::new(&*self.config.read().await, id, role, session, seq);
+        welcome.resumed = resumed;
+        welcome.control = self.control.lock().await.clone();
+        let target = Target {
+            scope: "ids".to_string(),
+            types: Vec::new(),
+            ids: vec![id.to_string()],
+        };
+        ClientMessage::server(
+            &self.id,
+            Some(target),
+            "WELCOME",
+            serde_json::to_value(welcome).unwrap(),
+        )
+    }
+
+    /// Перед регистрацией нового подключения: закрывает подключения с той же
+    /// сессией (её продолжили в новом), а при `duplicate_sessions = kick_old` —
+    /// и все прочие подключения пользователя.
+    pub async fn take_over(&self, id: &str, session: &str) {
+        self.close_sessions(
+            "Сессия продолжена в другом подключении",
+            LeaveReason::SessionClosed,
+            |_, _, s| s == session,
+        )
+        .await;
+        if self.config.read().await.duplicate_sessions == DuplicatePolicy::KickOld {
+            let reason = "Пользователь подключился заново";
+            self.close_sessions(reason, LeaveReason::SessionClosed, |uid, _, _| uid == id)
+                .await;
+            self.close_lp_sessions(reason, LeaveReason::SessionClosed, |uid, _| uid == id)
+                .await;
+        }
+    }
+
+    /// Закрывает WS и SSE подключения, для которых `pred(id, role, session)` истинно:
+    /// подписчик получает то, что уже стоит в его очереди, затем SESSION_CLOSED
+    /// с причиной; после этого WS закрывается, SSE‑поток завершается.
+    /// `leave` — причина ухода в USER_LEFT.
+    pub async fn close_sessions(
+        &self,
+        reason: &str,
+        leave: LeaveReason,
+        pred: impl Fn(&str, &str, &str) -> bool,
+    ) {
+        let msg = self.session_closed(reason);
+
+        let closed_ws = self
+            .ws_subs
+            .write()
+            .await
+            .extract(|sub| pred(&sub.id, &sub.role, &sub.session));
+        for sub in closed_ws {
+            // уже поставленное в очередь доставляется, SESSION_CLOSED — последним
+            sub.tx.outbox.close(Some(msg.clone()));
+            sub.tx.close.do_send(Disconnect {
+                reason: reason.to_string(),
+                leave,
+            });
+            self.user_disconnected(&sub.id, Transport::Ws, leave).await;
+        }
+
+        let closed_sse = self
+            .sse_senders
+            .write()
+            .await
+            .extract(|sub| pred(&sub.id, &sub.role, &sub.session));
+        for sub in closed_sse {
+            sub.tx.close(Some(SseEvent::from_message(&msg)));
+            self.user_disconnected(&sub.id, Transport::Sse, leave).await;
+        }
+    }
+
+    /// Закрывает LP‑сессии, для которых `pred(id, role)` истинно: SESSION_CLOSED
+    /// становится последним сообщением сессии, и она остаётся
+    /// зарегистрированной, пока клиент не заберёт эту пачку.
+    pub async fn close_lp_sessions(
+        &self,
+        reason: &str,
+        leave: LeaveReason,
+        pred: impl Fn(&str, &str) -> bool,
+    ) {
+        let closed: Vec<(String, Arc<LpSession>)> = self
+            .lp_senders
+            .read()
+            .await
+            .iter()
+            .filter(|sub| !sub.tx.is_closed() && pred(&sub.id, &sub.role))
+            .map(|sub| (sub.id.clone(), sub.tx.clone()))
+            .collect();
+        let msg = self.session_closed(reason);
+        for (id, session) in closed {
+            session.close(&msg.msg);
+            self.user_disconnected(&id, Transport::LongPolling, leave)
+                .await;
+        }
+    }
+
+    /// SESSION_CLOSED с причиной — последнее сообщение закрываемому подключению
+    pub fn session_closed(&self, reason: &str) -> ClientMessage {
+        ClientMessage::server(
+            &self.id,
+            None,
+            "SESSION_CLOSED",
+            serde_json::json!({ "reason": reason }),
+        )
+    }
+
+    /// WS‑подключение закрылось: снимаем с регистрации, если ещё не сняли
+    pub async fn remove_ws(&self, conn: u64, reason: LeaveReason) {
+        let removed = self.ws_subs.write().await.remove(conn);
+        if let Some(sub) = removed {
+            self.user_disconnected(&sub.id, Transport::Ws, reason).await;
+        }
+    }
+
+    /// SSE‑поток завершился (клиент ушёл): снимаем с регистрации, если ещё не сняли
+    pub async fn remove_sse(&self, conn: u64, reason: LeaveReason) {
+        let removed = self.sse_senders.write().await.remove(conn);
+        if let Some(sub) = removed {
+            self.user_disconnected(&sub.id, Transport::Sse, reason)
+                .await;
+        }
+    }
+
+    /// Событие присутствия всей комнате, кроме самого пользователя
+    /// (USER_LEFT — с причиной ухода). О скрытых ролях узнают только те, кто их видит.
+    pub async fn announce(
+        &self,
+        command: &str,
+        id: &str,
+        role: &str,
+        transport: Transport,
+        reason: Option<LeaveReason>,
+    ) {
+        let target = permissions::is_hidden(role).then(|| Target {
+            scope: "type".to_string(),
+            types: permissions::PRIVILEGED_ROLES
+                .iter()
+                .map(|r| r.to_string())
+                .collect(),
+            ids: Vec::new(),
+        });
+        let mut payload = serde_json::json!({ "id": id, "type": role, "connection": transport });
+        if let Some(reason) = reason {
+            payload["reason"] = serde_json::to_value(reason).unwrap_or(Value::Null);
+        }
+        let mut msg = ClientMessage::server(&self.id, target, command, payload);
+        msg.origin_sender_id = id.to_string();
+        deliver(self, msg).await;
+    }
+
+    /// Закрывает комнату: каждому подписчику уходит ROOM_CLOSED с причиной,
+    /// после чего WS‑соединения закрываются, а SSE и LP каналы отпускаются.
+    pub async fn close(&self, reason: &str) {
+        let msg = ClientMessage::server(
+            &self.id,
+            None,
+            "ROOM_CLOSED",
+            serde_json::json!({ "reason": reason }),
+        );
+        let ws = std::mem::take(&mut *self.ws_subs.write().await);
+        for sub in ws.iter() {
+            sub.tx.outbox.close(Some(msg.clone()));
+            sub.tx.close.do_send(Disconnect {
+                reason: reason.to_string(),
+                leave: LeaveReason::SessionClosed,
+            });
+        }
+        let sse = std::mem::take(&mut *self.sse_senders.write().await);
+        for sub in sse.iter() {
+            sub.tx.close(Some(SseEvent::from_message(&msg)));
+        }
+        let lps = std::mem::take(&mut *self.lp_senders.write().await);
+        for sub in lps.iter() {
+            sub.tx.push(&msg.msg);
+        }
+    }
+}
+
+/// Реестр комнат по room_id (пустой при создании, комнаты добавляет watcher)
+pub struct RoomRegistry {
+    rooms: RwLock<HashMap<String, Arc<Room>>>,
+    storage: Arc<dyn Storage>,
+    writer: Writer,
+}
+
+impl RoomRegistry {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            rooms: RwLock::new(HashMap::new()),
+            writer: Writer::start(storage.clone()),
+            storage,
+        }
+    }
+
+    /// Комната по id, `None` — такой комнаты нет
+    pub async fn get(&self, room_id: &str) -> Option<Arc<Room>> {
+        self.rooms.read().await.get(room_id).cloned()
+    }
+
+    /// Создаёт комнату или обновляет конфиг существующей.
+    /// Подписчики и сцена при обновлении сохраняются (кроме тех, кого
+    /// новый конфиг не допускает, см. `Room::reconfigure`), новая комната
+    /// поднимает сохранённые историю и сцену.
+    pub async fn upsert(&self, config: RoomConfig) {
+        if let Some(room) = self.get(&config.room).await {
+            room.reconfigure(config).await;
+            return;
+        }
+        // журнал читается вне цикла событий; комнату создаёт только watcher,
+        // так что вставить её между чтением и записью некому
+        let storage = self.storage.clone();
+        let room_id = config.room.clone();
+        let records = tokio::task::spawn_blocking(move || storage.load(&room_id))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|r| r)
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "Не удалось загрузить сохранённую комнату {}: {}",
+                    config.room, e
+                );
+                Vec::new()
+            });
+        let room = Room::new(config, records, self.writer.clone());
+        self.rooms
+            .write()
+            .await
+            .insert(room.id.clone(), Arc::new(room));
+    }
+
+    /// Убирает комнату из реестра; закрыть её подписчиков — забота вызывающего
+    pub async fn remove(&self, room_id: &str) -> Option<Arc<Room>> {
+        self.rooms.write().await.remove(room_id)
+    }
+
+    /// Все комнаты (для периодических задач вроде heartbeat)
+    pub async fn all(&self) -> Vec<Arc<Room>> {
+        self.rooms.read().await.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::file::JsonlStorage;
+    use crate::Subscriber;
+
+    fn config(students: &str, key: &str) -> RoomConfig {
+        RoomConfig::parse(&format!(
+            "room = room_1\n\
+             teacher = user_1\n\
+             authorised_students = {}\n\
+             sign_key = {}\n",
+            students, key
+        ))
+        .unwrap()
+    }
+
+    async fn poll(room: &Room, id: &str, conn: u64) -> Arc<LpSession> {
+        let session = Arc::new(LpSession::new(format!("s{}", conn)));
+        room.lp_senders.write().await.insert(Subscriber {
+            id: id.to_string(),
+            role: "ученик".to_string(),
+            session: session.id.clone(),
+            conn,
+            tx: session.clone(),
+        });
+        room.user_polled(id, "ученик").await;
+        session
+    }
+
+    /// Комната с хранилищем во временном каталоге `name`
+    fn room(name: &str, config: RoomConfig) -> (Room, std::path::PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("cubecast-rooms-{}-{}", name, std::process::id()));
+        let storage = JsonlStorage::new(dir.to_str().unwrap()).unwrap();
+        let writer = Writer::start(Arc::new(storage));
+        (Room::new(config, Vec::new(), writer), dir)
+    }
+
+    #[actix_web::test]
+    async fn reload_closes_revoked_sessions() {
+        let (room, dir) = room("reload", config("user_2, user_3", "secret"));
+        let user_2 = poll(&room, "user_2", 1).await;
+        let user_3 = poll(&room, "user_3", 2).await;
+
+        room.reconfigure(config("user_2", "secret")).await;
+        assert!(!user_2.is_closed());
+        assert!(user_3.is_closed());
+
+        room.reconfigure(config("user_2", "rotated")).await;
+        assert!(user_2.is_closed());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[actix_web::test]
+    async fn kick_old_take_over_closes_long_polling_sessions() {
+        let mut kick_old = config("user_2, user_3", "secret");
+        kick_old.duplicate_sessions = DuplicatePolicy::KickOld;
+        let (room, dir) = room("take-over", kick_old);
+        let user_2 = poll(&room, "user_2", 1).await;
+        let user_3 = poll(&room, "user_3", 2).await;
+
+        room.take_over("user_2", "s9").await;
+        assert!(user_2.is_closed());
+        assert!(!user_3.is_closed());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
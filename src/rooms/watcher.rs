@@ -0,0 +1,131 @@
+use super::RoomRegistry;
+use crate::config::RoomConfig;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Файл конфига, который уже видели
+struct WatchedFile {
+    modified: SystemTime,
+    /// Комната из этого файла; `None`, если файл ни разу не удалось разобрать
+    room_id: Option<String>,
+}
+
+/// Следит за каталогом с `*.room` файлами, опрашивая время изменения.
+/// Новый файл создаёт комнату, изменённый — обновляет её конфиг,
+/// удалённый — закрывает комнату. Ошибки разбора только логируются.
+pub struct RoomWatcher {
+    dir: PathBuf,
+    files: HashMap<PathBuf, WatchedFile>,
+}
+
+impl RoomWatcher {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Один проход по каталогу: применяет к реестру все изменения с прошлого раза.
+    pub async fn poll(&mut self, registry: &RoomRegistry) {
+        let current = match scan_dir(&self.dir) {
+            Ok(current) => current,
+            Err(e) => {
+                // каталог временно недоступен — комнаты не трогаем
+                eprintln!("Не удалось прочитать каталог {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+
+        // Удалённые файлы — закрываем их комнаты
+        let removed: Vec<PathBuf> = self
+            .files
+            .keys()
+            .filter(|path| !current.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in removed {
+            if let Some(WatchedFile {
+                room_id: Some(room_id),
+                ..
+            }) = self.files.remove(&path)
+            {
+                close_room(registry, &room_id, "конфиг комнаты удалён").await;
+            }
+        }
+
+        // Новые и изменённые файлы
+        for (path, modified) in current {
+            let prev_room = match self.files.get(&path) {
+                Some(known) if known.modified == modified => continue,
+                Some(known) => known.room_id.clone(),
+                None => None,
+            };
+
+            let room_id = match self.load(&path, registry).await {
+                Ok(config) => {
+                    // в файле сменили room — старая комната закрывается
+                    if let Some(old) = prev_room.filter(|old| *old != config.room) {
+                        close_room(registry, &old, "комната переименована").await;
+                    }
+                    Some(config.room)
+                }
+                Err(e) => {
+                    // битый файл: комната (если была) остаётся с прежним конфигом
+                    eprintln!("Ошибка в конфиге {}: {}", path.display(), e);
+                    prev_room
+                }
+            };
+            self.files.insert(path, WatchedFile { modified, room_id });
+        }
+    }
+
+    /// Читает файл и применяет его к реестру
+    async fn load(&self, path: &Path, registry: &RoomRegistry) -> Result<RoomConfig, String> {
+        let config = RoomConfig::load_from_file(path)?;
+        // одна комната — один файл
+        let duplicate = self.files.iter().any(|(other, known)| {
+            other != path && known.room_id.as_deref() == Some(config.room.as_str())
+        });
+        if duplicate {
+            return Err(format!("комната {} уже задана в другом файле", config.room));
+        }
+        registry.upsert(config.clone()).await;
+        print_room(&config);
+        Ok(config)
+    }
+}
+
+async fn close_room(registry: &RoomRegistry, room_id: &str, reason: &str) {
+    if let Some(room) = registry.remove(room_id).await {
+        println!("Room {} closed: {}", room_id, reason);
+        room.close(reason).await;
+    }
+}
+
+/// Все `*.room` файлы каталога со временем их изменения
+fn scan_dir(dir: &Path) -> std::io::Result<HashMap<PathBuf, SystemTime>> {
+    let mut files = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "room") {
+            // файл могли удалить между read_dir и metadata — просто пропускаем
+            if let Ok(modified) = fs::metadata(&path).and_then(|m| m.modified()) {
+                files.insert(path, modified);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Печатает, какая комната загружена. Ни sign_key, ни подписи пользователей
+/// в лог не попадают: подпись — постоянный пропуск в комнату.
+fn print_room(config: &RoomConfig) {
+    println!(
+        "Loaded config: room={}, authorised_students={}",
+        config.room,
+        config.authorised_students.len()
+    );
+}
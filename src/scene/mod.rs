@@ -0,0 +1,221 @@
+use crate::errors::{ApiError, ErrorCode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Координаты объекта на сцене
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+fn default_object_type() -> String {
+    "cube".to_string()
+}
+
+/// Один объект (кубик) на сцене
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Cube {
+    pub id: String,
+    #[serde(rename = "type", default = "default_object_type")]
+    pub object_type: String,
+    #[serde(default)]
+    pub position: Position,
+    #[serde(default)]
+    pub selected: bool,
+}
+
+/// Команды, которые читают или меняют сцену (разбираются реестром `commands`)
+#[derive(Clone, Debug)]
+pub enum SceneCommand {
+    Add(Cube),
+    Move { id: String, position: Position },
+    Select { id: String, selected: bool },
+    Remove { id: String },
+    Get,
+    Reset,
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::ObjectNotFound,
+        format!("Объекта {} нет на сцене", id),
+    )
+    .field("payload.id")
+}
+
+/// Команды, которые только обновляют существующий объект:
+/// более позднее обновление того же объекта заменяет раннее.
+const OBJECT_UPDATES: &[&str] = &["MOVE_CUBE", "SELECT_CUBE"];
+
+/// Частые обновления при перетаскивании: их можно рассылать окнами
+const DRAG_UPDATES: &[&str] = &["MOVE_CUBE"];
+
+/// Ключ (команда, id объекта) перетаскивания; остальные команды идут по одной
+pub fn drag_key<'a>(
+    command: Option<&'a str>,
+    payload: Option<&'a Value>,
+) -> Option<(&'a str, &'a str)> {
+    update_key(command, payload).filter(|(c, _)| DRAG_UPDATES.contains(c))
+}
+
+/// Ключ (команда, id объекта) для схлопывания обновлений; `None` — команду схлопывать нельзя
+pub fn update_key<'a>(
+    command: Option<&'a str>,
+    payload: Option<&'a Value>,
+) -> Option<(&'a str, &'a str)> {
+    let command = command.filter(|c| OBJECT_UPDATES.contains(c))?;
+    let id = payload?.get("id")?.as_str()?;
+    Some((command, id))
+}
+
+/// Состояние 3D-сцены одной комнаты
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Scene {
+    cubes: BTreeMap<String, Cube>,
+}
+
+/// Payload ответа STATE
+#[derive(Serialize)]
+struct ScenePayload<'a> {
+    cubes: Vec<&'a Cube>,
+}
+
+impl Scene {
+    /// Полное состояние сцены в виде payload
+    pub fn snapshot(&self) -> Value {
+        serde_json::to_value(ScenePayload {
+            cubes: self.cubes.values().collect(),
+        })
+        .unwrap_or(Value::Null)
+    }
+
+    /// Применяет команду к сцене.
+    /// Возвращает payload, который нужно разослать вместо исходного
+    /// (нормализованный объект или состояние сцены).
+    pub fn apply(&mut self, cmd: SceneCommand) -> Result<Option<Value>, ApiError> {
+        match cmd {
+            SceneCommand::Add(cube) => {
+                if self.cubes.contains_key(&cube.id) {
+                    return Err(ApiError::new(
+                        ErrorCode::ObjectExists,
+                        format!("Объект {} уже есть на сцене", cube.id),
+                    )
+                    .field("payload.id"));
+                }
+                let value = serde_json::to_value(&cube).ok();
+                self.cubes.insert(cube.id.clone(), cube);
+                Ok(value)
+            }
+            SceneCommand::Move { id, position } => {
+                let cube = self.get_mut(&id)?;
+                cube.position = position;
+                Ok(serde_json::to_value(&*cube).ok())
+            }
+            SceneCommand::Select { id, selected } => {
+                let cube = self.get_mut(&id)?;
+                cube.selected = selected;
+                Ok(serde_json::to_value(&*cube).ok())
+            }
+            SceneCommand::Remove { id } => {
+                self.cubes.remove(&id).ok_or_else(|| not_found(&id))?;
+                Ok(None)
+            }
+            SceneCommand::Get => Ok(Some(self.snapshot())),
+            SceneCommand::Reset => {
+                self.cubes.clear();
+                Ok(None)
+            }
+        }
+    }
+
+    fn get_mut(&mut self, id: &str) -> Result<&mut Cube, ApiError> {
+        self.cubes.get_mut(id).ok_or_else(|| not_found(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn cube(id: &str) -> Cube {
+        serde_json::from_value(json!({"id": id})).unwrap()
+    }
+
+    fn ids(scene: &Scene) -> Vec<String> {
+        scene.cubes.keys().cloned().collect()
+    }
+
+    #[test]
+    fn add_move_select_remove() {
+        let mut scene = Scene::default();
+        let added = scene.apply(SceneCommand::Add(cube("c1"))).unwrap().unwrap();
+        assert_eq!(added["type"], "cube");
+        assert_eq!(added["position"], json!({"x": 0.0, "y": 0.0, "z": 0.0}));
+        scene.apply(SceneCommand::Add(cube("c2"))).unwrap();
+
+        let position = Position {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        };
+        let moved = scene
+            .apply(SceneCommand::Move {
+                id: "c1".to_string(),
+                position,
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(moved["position"], json!({"x": 1.0, "y": 2.0, "z": 3.0}));
+        let selected = scene
+            .apply(SceneCommand::Select {
+                id: "c1".to_string(),
+                selected: true,
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(selected["selected"], true);
+
+        let removed = scene.apply(SceneCommand::Remove {
+            id: "c2".to_string(),
+        });
+        assert!(removed.unwrap().is_none());
+        assert_eq!(ids(&scene), vec!["c1"]);
+        let state = scene.apply(SceneCommand::Get).unwrap().unwrap();
+        assert_eq!(state["cubes"][0]["position"]["z"], 3.0);
+
+        scene.apply(SceneCommand::Reset).unwrap();
+        assert!(ids(&scene).is_empty());
+    }
+
+    #[test]
+    fn duplicate_and_missing_ids_are_rejected() {
+        let mut scene = Scene::default();
+        scene.apply(SceneCommand::Add(cube("c1"))).unwrap();
+        let err = scene.apply(SceneCommand::Add(cube("c1"))).unwrap_err();
+        assert_eq!(err.code, ErrorCode::ObjectExists);
+
+        let missing = [
+            SceneCommand::Move {
+                id: "c9".to_string(),
+                position: Position::default(),
+            },
+            SceneCommand::Select {
+                id: "c9".to_string(),
+                selected: true,
+            },
+            SceneCommand::Remove {
+                id: "c9".to_string(),
+            },
+        ];
+        for cmd in missing {
+            let err = scene.apply(cmd).unwrap_err();
+            assert_eq!(err.code, ErrorCode::ObjectNotFound);
+        }
+        assert_eq!(ids(&scene), vec!["c1"]);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::auth;
+use crate::config::RoomConfig;
+use crate::control::Control;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// Что делать, если пользователь подключается повторно (другая вкладка, устройство)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Все подключения работают одновременно
+    #[default]
+    Allow,
+    /// Новое подключение закрывает прежние подключения этого пользователя
+    KickOld,
+}
+
+impl DuplicatePolicy {
+    pub fn parse(val: &str) -> Result<Self, String> {
+        match val {
+            "allow" => Ok(DuplicatePolicy::Allow),
+            "kick_old" => Ok(DuplicatePolicy::KickOld),
+            other => Err(format!(
+                "Unknown duplicate_sessions `{}` (allow | kick_old)",
+                other
+            )),
+        }
+    }
+}
+
+/// Новый id сессии; выдаёт сервер, клиент его не выбирает
+pub fn new_session_id() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+/// Номер нового подключения (уникален в пределах процесса)
+pub fn next_connection() -> u64 {
+    NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Payload WELCOME — первое сообщение сервера в новом WS/SSE подключении
+/// (и ответ на HELLO по WS)
+#[derive(Serialize)]
+pub struct Welcome {
+    pub session_id: String,
+    pub room: String,
+    pub id: String,
+    #[serde(rename = "type")]
+    pub role: String,
+    /// Передаётся при переподключении (`?resume=` или HELLO), чтобы занять эту же сессию
+    pub resume_token: String,
+    /// Последний seq истории комнаты — с него можно продолжать
+    pub seq: u64,
+    /// Подключение продолжило прежнюю сессию
+    pub resumed: bool,
+    /// Текущее управление комнатой; дальше меняется событиями ROOM_CONTROL
+    pub control: Control,
+}
+
+impl Welcome {
+    pub fn new(config: &RoomConfig, id: &str, role: &str, session: &str, seq: u64) -> Self {
+        Self {
+            session_id: session.to_string(),
+            room: config.room.clone(),
+            id: id.to_string(),
+            role: role.to_string(),
+            resume_token: auth::resume_token(&config.sign_key, &config.room, id, role, session),
+            seq,
+            resumed: false,
+            control: Control::default(),
+        }
+    }
+}
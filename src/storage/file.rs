@@ -0,0 +1,150 @@
+use super::{Record, Storage};
+use crate::scene::Scene;
+use crate::ClientMessage;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// То же, что `Record`, но без копирования при записи
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RecordRef<'a> {
+    Message(&'a ClientMessage),
+    Scene { scene: &'a Scene },
+}
+
+/// Журнал в файлах: `<dir>/<room_id>.jsonl`, по одной JSON‑записи на строку.
+/// Сообщения только дописываются, сжатие переписывает файл целиком
+/// (через временный файл и rename, чтобы не потерять журнал при падении).
+pub struct JsonlStorage {
+    dir: PathBuf,
+    /// Открытые на дозапись файлы по room_id
+    files: Mutex<HashMap<String, File>>,
+    /// Комнаты, в журнал которых дописывали с прошлого сжатия
+    dirty: Mutex<HashSet<String>>,
+}
+
+impl JsonlStorage {
+    pub fn new(dir: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            files: Mutex::new(HashMap::new()),
+            dirty: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Путь журнала комнаты. room_id берётся из конфига, в имя файла
+    /// буквы, цифры, `-` и `_` идут как есть, остальное (и сам `%`) —
+    /// байтами UTF‑8 в виде `%XX`: разные комнаты не делят один файл.
+    fn path(&self, room_id: &str) -> PathBuf {
+        let mut name = String::with_capacity(room_id.len());
+        for c in room_id.chars() {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                name.push(c);
+            } else {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    name.push_str(&format!("%{:02X}", b));
+                }
+            }
+        }
+        self.dir.join(format!("{}.jsonl", name))
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn write_record(out: &mut impl Write, record: &RecordRef) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")
+}
+
+impl Storage for JsonlStorage {
+    fn load(&self, room_id: &str) -> io::Result<Vec<Record>> {
+        let path = self.path(room_id);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut records = Vec::new();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // Битая строка (например, недописанная при падении) пропускается
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => eprintln!("{}:{}: пропущена запись: {}", path.display(), n + 1, e),
+            }
+        }
+        Ok(records)
+    }
+
+    fn append(&self, room_id: &str, msg: &ClientMessage) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = match files.get_mut(room_id) {
+            Some(file) => file,
+            None => {
+                let file = open_append(&self.path(room_id))?;
+                files.entry(room_id.to_string()).or_insert(file)
+            }
+        };
+        let mut line = serde_json::to_vec(&RecordRef::Message(msg))?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        self.dirty.lock().unwrap().insert(room_id.to_string());
+        Ok(())
+    }
+
+    fn compact(&self, room_id: &str, messages: &[&ClientMessage], scene: &Scene) -> io::Result<()> {
+        let path = self.path(room_id);
+        if !self.dirty.lock().unwrap().contains(room_id) && path.exists() {
+            return Ok(());
+        }
+
+        // Снимок сцены — последним: всё, что дописано после него, применяется поверх
+        let tmp = path.with_extension("jsonl.tmp");
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            for msg in messages {
+                write_record(&mut out, &RecordRef::Message(msg))?;
+            }
+            write_record(&mut out, &RecordRef::Scene { scene })?;
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+
+        let mut files = self.files.lock().unwrap();
+        fs::rename(&tmp, &path)?;
+        // старый дескриптор смотрит на удалённый файл — открываем заново
+        files.insert(room_id.to_string(), open_append(&path)?);
+        self.dirty.lock().unwrap().remove(room_id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_ids_do_not_share_a_journal() {
+        let storage = JsonlStorage {
+            dir: PathBuf::from("data"),
+            files: Mutex::new(HashMap::new()),
+            dirty: Mutex::new(HashSet::new()),
+        };
+        let ids = ["room_1", "room.1", "room%2E1", "a/b", "a_b", "../x", "урок"];
+        let paths: HashSet<PathBuf> = ids.iter().map(|id| storage.path(id)).collect();
+        assert_eq!(paths.len(), ids.len());
+        assert_eq!(storage.path("room_1"), Path::new("data/room_1.jsonl"));
+        assert_eq!(storage.path("a/b"), Path::new("data/a%2Fb.jsonl"));
+    }
+}
@@ -0,0 +1,127 @@
+pub mod file;
+
+use crate::commands::{self, Command};
+use crate::scene::Scene;
+use crate::ClientMessage;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Запись журнала комнаты: разосланное сообщение или снимок сцены
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Record {
+    Message(Box<ClientMessage>),
+    Scene { scene: Scene },
+}
+
+/// Хранилище истории и сцены комнат, чтобы перезапуск не обнулял занятие.
+/// Методы блокируют поток: записи вызываются из потока `Writer` по одной,
+/// в порядке рассылки, а чтение — через `spawn_blocking`.
+pub trait Storage: Send + Sync {
+    /// Все записи комнаты в порядке записи (пусто — комнату ещё не сохраняли)
+    fn load(&self, room_id: &str) -> io::Result<Vec<Record>>;
+
+    /// Дописывает разосланное сообщение
+    fn append(&self, room_id: &str, msg: &ClientMessage) -> io::Result<()>;
+
+    /// Сжатие: журнал заменяется сообщениями из истории и снимком сцены.
+    /// Если с прошлого сжатия ничего не дописано, можно ничего не делать.
+    fn compact(&self, room_id: &str, messages: &[&ClientMessage], scene: &Scene) -> io::Result<()>;
+}
+
+/// Задание потоку записи
+enum Job {
+    Append {
+        room_id: String,
+        msg: Box<ClientMessage>,
+    },
+    Compact {
+        room_id: String,
+        messages: Vec<ClientMessage>,
+        scene: Scene,
+    },
+}
+
+/// Очередь записи в хранилище. Диск трогает отдельный поток, а не цикл событий
+/// actix, и блокировки комнаты на время записи не держатся. Задания ставятся
+/// в очередь под блокировкой истории и выполняются по одному, поэтому записи
+/// комнаты ложатся на диск в порядке seq, а сжатие — между ними, где и было.
+#[derive(Clone)]
+pub struct Writer {
+    tx: mpsc::UnboundedSender<Job>,
+}
+
+impl Writer {
+    /// Запускает поток записи; он работает, пока жив хоть один `Writer`
+    pub fn start(storage: Arc<dyn Storage>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("storage".to_string())
+            .spawn(move || {
+                while let Some(job) = rx.blocking_recv() {
+                    match job {
+                        Job::Append { room_id, msg } => {
+                            if let Err(e) = storage.append(&room_id, &msg) {
+                                eprintln!(
+                                    "Не удалось сохранить сообщение комнаты {}: {}",
+                                    room_id, e
+                                );
+                            }
+                        }
+                        Job::Compact {
+                            room_id,
+                            messages,
+                            scene,
+                        } => {
+                            let messages: Vec<&ClientMessage> = messages.iter().collect();
+                            if let Err(e) = storage.compact(&room_id, &messages, &scene) {
+                                eprintln!("Не удалось сжать журнал комнаты {}: {}", room_id, e);
+                            }
+                        }
+                    }
+                }
+            })
+            .expect("не удалось запустить поток записи хранилища");
+        Self { tx }
+    }
+
+    /// Дописать разосланное сообщение
+    pub fn append(&self, room_id: &str, msg: ClientMessage) {
+        let _ = self.tx.send(Job::Append {
+            room_id: room_id.to_string(),
+            msg: Box::new(msg),
+        });
+    }
+
+    /// Сжать журнал до этих сообщений и снимка сцены
+    pub fn compact(&self, room_id: &str, messages: Vec<ClientMessage>, scene: Scene) {
+        let _ = self.tx.send(Job::Compact {
+            room_id: room_id.to_string(),
+            messages,
+            scene,
+        });
+    }
+}
+
+/// Восстанавливает историю и сцену из записей.
+/// Сцена — последний снимок, к которому применены команды сцены,
+/// разосланные после него (в истории они уже с нормализованным payload).
+pub fn restore(records: Vec<Record>) -> (Vec<ClientMessage>, Scene) {
+    let mut messages = Vec::new();
+    let mut scene = Scene::default();
+    for record in records {
+        match record {
+            Record::Scene { scene: snapshot } => scene = snapshot,
+            Record::Message(msg) => {
+                if let Ok(Command::Scene(cmd)) = commands::parse(&msg.msg) {
+                    // команда уже применялась до сохранения, ошибки тут не важны
+                    let _ = scene.apply(cmd);
+                }
+                messages.push(*msg);
+            }
+        }
+    }
+    (messages, scene)
+}
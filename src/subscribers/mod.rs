@@ -0,0 +1,212 @@
+use crate::{ClientMessage, Subscriber};
+use std::collections::{HashMap, HashSet};
+
+/// Подписчики одного транспорта в комнате с индексами по id пользователя и роли.
+/// Адресная рассылка (`ids`, `type`) обходит только адресатов,
+/// а не все подключения комнаты.
+///
+/// id и роль подписчика входят в индексы, поэтому меняются только через
+/// `set_role`; остальные поля можно менять через `get_mut`.
+pub struct Subscribers<T> {
+    /// Подписчики по номеру подключения
+    by_conn: HashMap<u64, Subscriber<T>>,
+    by_id: HashMap<String, HashSet<u64>>,
+    by_role: HashMap<String, HashSet<u64>>,
+}
+
+impl<T> Default for Subscribers<T> {
+    fn default() -> Self {
+        Self {
+            by_conn: HashMap::new(),
+            by_id: HashMap::new(),
+            by_role: HashMap::new(),
+        }
+    }
+}
+
+fn index(map: &mut HashMap<String, HashSet<u64>>, key: &str, conn: u64) {
+    map.entry(key.to_string()).or_default().insert(conn);
+}
+
+fn unindex(map: &mut HashMap<String, HashSet<u64>>, key: &str, conn: u64) {
+    if let Some(conns) = map.get_mut(key) {
+        conns.remove(&conn);
+        if conns.is_empty() {
+            map.remove(key);
+        }
+    }
+}
+
+impl<T> Subscribers<T> {
+    pub fn insert(&mut self, sub: Subscriber<T>) {
+        // номера подключений не переиспользуются, но индексы держим честными
+        self.remove(sub.conn);
+        index(&mut self.by_id, &sub.id, sub.conn);
+        index(&mut self.by_role, &sub.role, sub.conn);
+        self.by_conn.insert(sub.conn, sub);
+    }
+
+    pub fn remove(&mut self, conn: u64) -> Option<Subscriber<T>> {
+        let sub = self.by_conn.remove(&conn)?;
+        unindex(&mut self.by_id, &sub.id, conn);
+        unindex(&mut self.by_role, &sub.role, conn);
+        Some(sub)
+    }
+
+    /// Убирает и возвращает подписчиков, для которых `pred` истинно
+    pub fn extract(&mut self, pred: impl Fn(&Subscriber<T>) -> bool) -> Vec<Subscriber<T>> {
+        let conns: Vec<u64> = self
+            .by_conn
+            .values()
+            .filter(|sub| pred(sub))
+            .map(|sub| sub.conn)
+            .collect();
+        conns.into_iter().filter_map(|c| self.remove(c)).collect()
+    }
+
+    /// Оставляет только подписчиков, для которых `keep` истинно; возвращает убранных
+    pub fn retain(&mut self, keep: impl Fn(&Subscriber<T>) -> bool) -> Vec<Subscriber<T>> {
+        self.extract(|sub| !keep(sub))
+    }
+
+    pub fn get_mut(&mut self, conn: u64) -> Option<&mut Subscriber<T>> {
+        self.by_conn.get_mut(&conn)
+    }
+
+    pub fn set_role(&mut self, conn: u64, role: &str) {
+        let Some(sub) = self.by_conn.get_mut(&conn) else {
+            return;
+        };
+        if sub.role != role {
+            let old = std::mem::replace(&mut sub.role, role.to_string());
+            unindex(&mut self.by_role, &old, conn);
+            index(&mut self.by_role, role, conn);
+        }
+    }
+
+    /// Подключения пользователя
+    pub fn of_user<'a>(&'a self, id: &str) -> impl Iterator<Item = &'a Subscriber<T>> + 'a {
+        self.by_id
+            .get(id)
+            .into_iter()
+            .flatten()
+            .filter_map(|c| self.by_conn.get(c))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Subscriber<T>> {
+        self.by_conn.values()
+    }
+
+    /// Адресаты сообщения (отправитель своё не получает).
+    /// Для `ids` и `type` обходятся только индексы адресатов.
+    pub fn recipients<'a>(
+        &'a self,
+        msg: &'a ClientMessage,
+    ) -> Box<dyn Iterator<Item = &'a Subscriber<T>> + 'a> {
+        let keys = match msg.msg.target.as_ref() {
+            Some(t) if t.scope == "ids" => Some((&self.by_id, &t.ids)),
+            Some(t) if t.scope == "type" => Some((&self.by_role, &t.types)),
+            _ => None,
+        };
+        match keys {
+            Some((index, keys)) => {
+                // ключи в target могут повторяться — каждого адресата берём один раз
+                let mut seen = HashSet::new();
+                let conns: Vec<u64> = keys
+                    .iter()
+                    .filter_map(|k| index.get(k))
+                    .flatten()
+                    .copied()
+                    .filter(|c| seen.insert(*c))
+                    .collect();
+                Box::new(
+                    conns
+                        .into_iter()
+                        .filter_map(|c| self.by_conn.get(&c))
+                        .filter(|sub| msg.is_for(sub)),
+                )
+            }
+            None => Box::new(self.by_conn.values().filter(|sub| msg.is_for(sub))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validator::message::Target;
+    use serde_json::Value;
+
+    fn subscribers() -> Subscribers<()> {
+        let mut subs = Subscribers::default();
+        let users = [
+            ("user_1", "учитель"),
+            ("user_2", "ученик"),
+            ("user_2", "ученик"),
+            ("user_3", "ученик"),
+            ("user_9", "наблюдатель"),
+        ];
+        for (conn, (id, role)) in users.into_iter().enumerate() {
+            subs.insert(Subscriber {
+                id: id.to_string(),
+                role: role.to_string(),
+                session: format!("s{}", conn),
+                conn: conn as u64,
+                tx: (),
+            });
+        }
+        subs
+    }
+
+    fn message(from: &str, scope: &str, keys: &[&str]) -> ClientMessage {
+        let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        let target = Target {
+            scope: scope.to_string(),
+            types: if scope == "type" {
+                keys.clone()
+            } else {
+                Vec::new()
+            },
+            ids: if scope == "ids" { keys } else { Vec::new() },
+        };
+        let mut msg = ClientMessage::server("room_1", Some(target), "TEST", Value::Null);
+        msg.origin_sender_id = from.to_string();
+        msg
+    }
+
+    fn conns(subs: &Subscribers<()>, msg: &ClientMessage) -> Vec<u64> {
+        let mut conns: Vec<u64> = subs.recipients(msg).map(|sub| sub.conn).collect();
+        conns.sort();
+        conns
+    }
+
+    #[test]
+    fn targeted_messages_reach_only_addressees() {
+        let subs = subscribers();
+        // все вкладки пользователя, повтор в target — один раз
+        let msg = message("user_1", "ids", &["user_2", "user_2", "user_404"]);
+        assert_eq!(conns(&subs, &msg), vec![1, 2]);
+        let msg = message("user_2", "type", &["ученик"]);
+        assert_eq!(conns(&subs, &msg), vec![3]);
+        let msg = message("user_1", "all", &[]);
+        assert_eq!(conns(&subs, &msg), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn indexes_follow_role_changes_and_removal() {
+        let mut subs = subscribers();
+        subs.set_role(3, "наблюдатель");
+        let msg = message("user_1", "type", &["наблюдатель"]);
+        assert_eq!(conns(&subs, &msg), vec![3, 4]);
+
+        subs.remove(1);
+        let removed = subs.extract(|sub| sub.id == "user_9");
+        assert_eq!(removed.len(), 1);
+        assert_eq!(
+            conns(&subs, &message("user_1", "ids", &["user_2"])),
+            vec![2]
+        );
+        assert_eq!(conns(&subs, &msg), vec![3]);
+        assert_eq!(subs.of_user("user_9").count(), 0);
+    }
+}
@@ -0,0 +1,55 @@
+//! Мок более глубокого бэкенда (upstream) для тестов пересылки команд.
+//!
+//! На POST /command отвечает тем же request_id и payload вида
+//! {"echo": <payload>, "command": <command>, "room_id": <room_id>}.
+//! `delay` задерживает ответ — для проверки таймаута, `status` — код ответа.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[derive(Clone, Copy)]
+pub struct MockUpstream {
+    pub delay: Duration,
+    pub status: StatusCode,
+}
+
+impl Default for MockUpstream {
+    fn default() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            status: StatusCode::OK,
+        }
+    }
+}
+
+async fn command(body: web::Json<Value>, mock: web::Data<MockUpstream>) -> impl Responder {
+    tokio::time::sleep(mock.delay).await;
+    HttpResponse::build(mock.status).json(json!({
+        "request_id": body["request_id"],
+        "payload": {
+            "echo": body["payload"],
+            "command": body["command"],
+            "room_id": body["room_id"],
+        }
+    }))
+}
+
+impl MockUpstream {
+    /// Запускает мок на свободном порту, возвращает URL для `upstream`
+    pub fn start(self) -> String {
+        let mock = web::Data::new(self);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(mock.clone())
+                .route("/command", web::post().to(command))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .expect("мок upstream не смог занять порт");
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}/command", addr)
+    }
+}
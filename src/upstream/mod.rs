@@ -0,0 +1,84 @@
+#[cfg(test)]
+pub mod mock;
+
+use crate::errors::{ApiError, ErrorCode};
+use crate::validator::message::{IncomingMessage, Sender};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Сквозной счётчик id запросов к upstream
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Что уходит в upstream (POST JSON)
+#[derive(Serialize)]
+struct UpstreamRequest<'a> {
+    request_id: u64,
+    room_id: &'a str,
+    sender: &'a Sender,
+    command: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<&'a Value>,
+}
+
+/// Ответ upstream. `request_id` должен совпадать с отправленным,
+/// `command` — какую команду разослать (по умолчанию исходная).
+#[derive(Debug, Deserialize)]
+pub struct UpstreamResponse {
+    pub request_id: u64,
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub payload: Option<Value>,
+}
+
+/// Пересылает команду в upstream и ждёт ответа не дольше `timeout`.
+pub async fn forward(
+    client: &reqwest::Client,
+    url: &str,
+    timeout: Duration,
+    msg: &IncomingMessage,
+) -> Result<UpstreamResponse, ApiError> {
+    let failed = |message: String| ApiError::new(ErrorCode::UpstreamError, message);
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let command = msg.msg_command.as_deref().unwrap_or_default();
+    let body = UpstreamRequest {
+        request_id,
+        room_id: &msg.room_id,
+        sender: &msg.sender,
+        command,
+        payload: msg.payload.as_ref(),
+    };
+
+    let response = client
+        .post(url)
+        .timeout(timeout)
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| {
+            if e.is_timeout() {
+                ApiError::new(
+                    ErrorCode::UpstreamTimeout,
+                    format!("Upstream не ответил за {} мс", timeout.as_millis()),
+                )
+            } else {
+                failed(format!("Upstream недоступен: {}", e))
+            }
+        })?;
+    if !response.status().is_success() {
+        return Err(failed(format!("Upstream вернул {}", response.status())));
+    }
+    let reply: UpstreamResponse = response
+        .json()
+        .await
+        .map_err(|e| failed(format!("Некорректный ответ upstream: {}", e)))?;
+    if reply.request_id != request_id {
+        return Err(failed(format!(
+            "Upstream ответил на чужой запрос: ждали {}, пришёл {}",
+            request_id, reply.request_id
+        )));
+    }
+    Ok(reply)
+}
@@ -1,6 +1,8 @@
+use crate::permissions;
+use crate::presence::UserInfo;
 use crate::validator::extractor::ValidatedJson;
 use crate::validator::message::IncomingMessage;
-use crate::{ws::broadcast::GetCount, AppState, BroadcastServer, ROOM_CONFIG};
+use crate::{ws::broadcast::GetCount, AppState, BroadcastServer};
 use actix::prelude::*;
 use actix_web::{web, HttpResponse, Responder};
 use serde::Serialize;
@@ -15,15 +17,6 @@ struct SenderInfo {
     role: String, // Чтобы в JSON получилось "type": "server"
 }
 
-/// Описание одного пользователя в списке.
-#[derive(Serialize)]
-struct UserInfo {
-    id: String,
-    #[serde(rename = "type")]
-    role: String, // "учитель" | "ученик" | "наблюдатель"
-    connection: String, // "ws" | "sse" | "long_polling"
-}
-
 #[derive(Serialize)]
 struct ConnectionsCount {
     ws: usize,
@@ -50,24 +43,49 @@ struct UserListMessage {
 
 // Возвращает количество подписчиков по типам: WebSocket, SSE и Long Polling
 /// Строит структуру UserListMessage (без HTTP-обёртки).
-async fn build_user_list(state: &AppState, srv: &Addr<BroadcastServer>) -> UserListMessage {
+/// Скрытые роли (наблюдатель, ADMIN) попадают в список и счётчики,
+/// только если запрашивающий их видит.
+async fn build_user_list(
+    state: &AppState,
+    srv: &Addr<BroadcastServer>,
+    room_id: &str,
+    requester_role: &str,
+) -> UserListMessage {
+    let include_hidden = permissions::sees_hidden(requester_role);
+    let visible = |role: &str| include_hidden || !permissions::is_hidden(role);
+
     // получаем количество WebSocket-подписчиков
-    let ws_count = srv.send(GetCount).await.unwrap_or(0);
-    // получаем количество SSE-подписчиков
-    let sse_count = state.sse_senders.lock().await.len();
-    // получаем количество Long Polling-подписчиков
-    let lp_count = state.lp_senders.lock().await.len();
+    let ws_count = srv
+        .send(GetCount {
+            room_id: room_id.to_string(),
+            include_hidden,
+        })
+        .await
+        .unwrap_or(0);
+    let (sse_count, lp_count, users) = match state.rooms.get(room_id).await {
+        // получаем количество SSE- и Long Polling-подписчиков и список из присутствия
+        Some(room) => {
+            let sse = room.sse_senders.read().await;
+            let lp = room.lp_senders.read().await;
+            (
+                sse.iter().filter(|sub| visible(&sub.role)).count(),
+                lp.iter()
+                    .filter(|sub| visible(&sub.role) && !sub.tx.is_closed())
+                    .count(),
+                room.presence.lock().await.list(include_hidden),
+            )
+        }
+        None => (0, 0, Vec::new()),
+    };
 
     let counts = ConnectionsCount {
         ws: ws_count,
         sse: sse_count,
         lp: lp_count,
     };
-    // TODO: собрать список пользователей
-    let users: Vec<UserInfo> = Vec::new();
 
     UserListMessage {
-        room_id: ROOM_CONFIG.room.clone(),
+        room_id: room_id.to_string(),
         sender: SenderInfo {
             id: "server".into(),
             role: "server".into(),
@@ -81,10 +99,10 @@ async fn build_user_list(state: &AppState, srv: &Addr<BroadcastServer>) -> UserL
 pub async fn get_users_list(
     state: web::Data<AppState>,
     srv: web::Data<Addr<BroadcastServer>>,
-    _msg: ValidatedJson<IncomingMessage>,
+    msg: ValidatedJson<IncomingMessage>,
 ) -> impl Responder {
-    // _msg.0 — уже валидный IncomingMessage
-    //  srv.get_ref().do_send(ClientMessage(_msg.0.clone()));
-    let message = build_user_list(&state, &srv).await;
+    // msg.0 — уже валидный IncomingMessage
+    //  srv.get_ref().do_send(ClientMessage(msg.0.clone()));
+    let message = build_user_list(&state, &srv, &msg.0.room_id, &msg.0.sender.sender_type).await;
     HttpResponse::Ok().json(message)
 }
@@ -1,21 +1,49 @@
 use super::message::Validate;
-use actix_web::{dev::Payload, error::ErrorBadRequest, web::Json, Error, FromRequest, HttpRequest};
+use crate::auth::{self, Authenticate};
+use crate::errors::{ApiError, ErrorCode};
+use crate::AppState;
+use actix_web::{
+    dev::Payload,
+    web::{Data, Json},
+    Error, FromRequest, HttpRequest,
+};
 use futures_core::future::LocalBoxFuture;
 
 pub struct ValidatedJson<T>(pub T);
 
 impl<T> FromRequest for ValidatedJson<T>
 where
-    T: serde::de::DeserializeOwned + Validate + 'static,
+    T: serde::de::DeserializeOwned + Validate + Authenticate + 'static,
 {
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
         let fut = Json::<T>::from_request(req, payload);
+        let state = req.app_data::<Data<AppState>>().cloned();
+        let token = auth::request_token(req);
         Box::pin(async move {
-            let Json(inner) = fut.await.map_err(ErrorBadRequest)?;
-            inner.validate().map_err(ErrorBadRequest)?;
+            let Json(mut inner) = fut
+                .await
+                .map_err(|e| ApiError::new(ErrorCode::MalformedMessage, e.to_string()))?;
+            let state =
+                state.ok_or_else(|| ApiError::new(ErrorCode::Internal, "AppState не настроен"))?;
+            // С токеном отправитель — тот, кому выдан токен, а не указанный в теле
+            if let Some(token) = token {
+                let (room, claims) = auth::verify_token_in(&state.rooms, &token).await?;
+                room.admit(&claims.id).await?;
+                inner.use_token(claims)?;
+                inner.validate()?;
+                return Ok(ValidatedJson(inner));
+            }
+            inner.validate()?;
+            let room = state
+                .rooms
+                .get(inner.room_id())
+                .await
+                .ok_or_else(|| auth::unknown_room(inner.room_id()))?;
+            inner.authenticate(&*room.config.read().await)?;
+            room.admit(inner.sender_id()).await?;
             Ok(ValidatedJson(inner))
         })
     }
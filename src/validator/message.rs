@@ -1,15 +1,24 @@
+use crate::errors::{ApiError, ErrorCode};
+use crate::permissions;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
 pub struct Sender {
     pub id: String,
+    /// Роль отправителя
     #[serde(rename = "type")]
     pub sender_type: String,
+    /// Подпись отправителя (см. auth::sign). Никогда не рассылается дальше.
+    #[serde(default, skip_serializing)]
+    pub sign: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// Кому доставить сообщение; без target — всем в комнате
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Target {
+    #[schemars(extend("enum" = ["all", "type", "ids"]))]
     pub scope: String,
     #[serde(default)]
     pub types: Vec<String>,
@@ -17,74 +26,109 @@ pub struct Target {
     pub ids: Vec<String>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+impl Target {
+    /// Адресовано ли сообщение подписчику с данным id и ролью.
+    /// Предполагается, что target уже прошёл валидацию.
+    pub fn matches(&self, id: &str, role: &str) -> bool {
+        match self.scope.as_str() {
+            "all" => true,
+            "type" => self.types.iter().any(|t| t == role),
+            "ids" => self.ids.iter().any(|i| i == id),
+            _ => false,
+        }
+    }
+}
+
+/// Конверт сообщения для всех транспортов
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct IncomingMessage {
-    #[serde(rename = "room_id")]
+    /// С токеном доступа room_id и sender можно не указывать: их задаёт токен
+    #[serde(rename = "room_id", default)]
     pub room_id: String,
 
+    #[serde(default)]
     pub sender: Sender,
 
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target: Option<Target>,
 
+    /// Команда из реестра (или пересылаемая комнатой в upstream);
+    /// без неё сообщение просто рассылается
     #[serde(rename = "command", default, skip_serializing_if = "Option::is_none")]
     pub msg_command: Option<String>,
 
+    /// Данные команды, схема — у каждой команды своя
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub payload: Option<Value>,
+
+    /// Номер сообщения в истории комнаты; присваивает сервер при рассылке
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+
+    /// id сообщения от клиента: с ним отправитель получает ACK,
+    /// а ответы на команду (STATE, ERROR, ответ upstream) несут тот же id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<String>,
 }
 
 pub trait Validate {
-    fn validate(&self) -> Result<(), String>;
+    fn validate(&self) -> Result<(), ApiError>;
+}
+
+fn invalid(field: &str, message: impl Into<String>) -> ApiError {
+    ApiError::new(ErrorCode::InvalidMessage, message).field(field)
 }
 
 impl Validate for IncomingMessage {
-    fn validate(&self) -> Result<(), String> {
+    fn validate(&self) -> Result<(), ApiError> {
         if self.room_id.trim().is_empty() {
-            return Err("Поле room_id не должно быть пустым".into());
+            return Err(invalid("room_id", "Поле room_id не должно быть пустым"));
         }
-        /*
-        "учитель" - проводит занятие
-        "ученик"  - участник занятий
-        "наблюдатель" - наблюает втайне (родитель / невидимый )
-        "ADMIN"       - тоже что наблюдатель (невидимый) и учитель (все права)
-        "heartbeat"   - для PING-сообщений сервера
-        */
-        match self.sender.sender_type.as_str() {
-            "учитель" | "ученик" | "наблюдатель" | "ADMIN" | "heartbeat" => {
-            }
-            other => {
-                return Err(format!(
-                    "Неверный sender.type: {}. Должно быть 'учитель', 'ученик' или 'наблюдатель'",
-                    other
-                ))
-            }
+        // Роли и их права — в таблице permissions::PERMISSIONS;
+        // "heartbeat" — роль PING‑сообщений сервера, клиенту её запрещает authorize
+        let role = self.sender.sender_type.as_str();
+        if permissions::for_role(role).is_none() && role != "heartbeat" {
+            let roles: Vec<String> = permissions::PERMISSIONS
+                .iter()
+                .map(|p| format!("'{}'", p.role))
+                .collect();
+            return Err(invalid(
+                "sender.type",
+                format!(
+                    "Неверный sender.type: {}. Должно быть одно из: {}",
+                    role,
+                    roles.join(", ")
+                ),
+            ));
         }
         if let Some(target) = &self.target {
             match target.scope.as_str() {
                 "all" => {}
                 "type" => {
                     if target.types.is_empty() {
-                        return Err(
-                            "Когда target.scope = 'type', поле target.types не должно быть пустым"
-                                .into(),
-                        );
+                        return Err(invalid(
+                            "target.types",
+                            "Когда target.scope = 'type', поле target.types не должно быть пустым",
+                        ));
                     }
                 }
                 "ids" => {
                     if target.ids.is_empty() {
-                        return Err(
-                            "Когда target.scope = 'ids', поле target.ids не должно быть пустым"
-                                .into(),
-                        );
+                        return Err(invalid(
+                            "target.ids",
+                            "Когда target.scope = 'ids', поле target.ids не должно быть пустым",
+                        ));
                     }
                 }
                 other => {
-                    return Err(format!(
-                        "Неверное target.scope: '{}'. Должно быть 'all', 'type' или 'ids'",
-                        other
+                    return Err(invalid(
+                        "target.scope",
+                        format!(
+                            "Неверное target.scope: '{}'. Должно быть 'all', 'type' или 'ids'",
+                            other
+                        ),
                     ))
                 }
             }
@@ -100,3 +144,29 @@ impl Validate for IncomingMessage {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(role: &str) -> IncomingMessage {
+        serde_json::from_value(serde_json::json!({
+            "room_id": "room_1",
+            "sender": {"id": "user_1", "type": role},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn sender_type_is_checked_against_permissions_table() {
+        for perms in permissions::PERMISSIONS {
+            assert!(from(perms.role).validate().is_ok(), "{}", perms.role);
+        }
+        let err = from("директор").validate().unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("sender.type"));
+        let message = err.message.unwrap();
+        for perms in permissions::PERMISSIONS {
+            assert!(message.contains(perms.role), "{}", message);
+        }
+    }
+}
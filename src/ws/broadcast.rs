@@ -1,12 +1,36 @@
-use crate::{validator::message::IncomingMessage, AppState, ROOM_CONFIG};
+use crate::auth;
+use crate::commands::{self, Command};
+use crate::control::ControlCommand;
+use crate::errors::{ApiError, ErrorCode};
+use crate::outbox::{Coalesce, Outbox, Pushed};
+use crate::permissions;
+use crate::presence::{LeaveReason, Transport};
+use crate::rooms::{Room, SseEvent};
+use crate::scene::{self, SceneCommand};
+use crate::upstream;
+use crate::validator::message::{IncomingMessage, Sender, Target};
+use crate::ws::Disconnect;
+use crate::{AppState, Subscriber};
 use actix::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::ControlFlow;
+use std::sync::Arc;
 use std::time::Duration;
 
-static PING_INTERVAL: u64 = 15;
+/// Как часто сжимать журналы комнат в хранилище, сек
+static COMPACT_INTERVAL: u64 = 60;
+/// Причина отключения подписчика, не успевающего забирать сообщения
+static SLOW_CONSUMER: &str = "Клиент не успевает получать сообщения";
+
+impl Coalesce for ClientMessage {
+    fn coalesce_key(&self) -> Option<(&str, &str)> {
+        scene::update_key(self.msg.msg_command.as_deref(), self.msg.payload.as_ref())
+    }
+}
 
 /// Сообщение, которое идёт через актор BroadcastServer.
-#[derive(Message, Clone, Debug, Serialize)]
+#[derive(Message, Clone, Debug, Deserialize, Serialize)]
 #[rtype(result = "()")]
 pub struct ClientMessage {
     pub msg: IncomingMessage,
@@ -14,27 +38,221 @@ pub struct ClientMessage {
     pub origin_sender_id: String,
 }
 
+impl ClientMessage {
+    /// Должен ли подписчик получить это сообщение:
+    /// отправитель своё не получает, остальные — согласно target (нет target = всем).
+    pub fn is_for<T>(&self, sub: &Subscriber<T>) -> bool {
+        self.is_for_user(&sub.id, &sub.role)
+    }
+
+    /// То же по id и роли — для повторной доставки из истории
+    pub fn is_for_user(&self, id: &str, role: &str) -> bool {
+        if id == self.origin_sender_id {
+            return false;
+        }
+        self.msg.target.as_ref().is_none_or(|t| t.matches(id, role))
+    }
+
+    /// Сообщение от имени сервера всей комнате (или адресатам target).
+    pub fn server(
+        room_id: &str,
+        target: Option<Target>,
+        command: &str,
+        payload: Value,
+    ) -> ClientMessage {
+        ClientMessage {
+            msg: IncomingMessage {
+                room_id: room_id.to_string(),
+                sender: Sender {
+                    id: "server".to_string(),
+                    sender_type: "server".to_string(),
+                    sign: None,
+                },
+                target,
+                msg_command: Some(command.to_string()),
+                payload: Some(payload),
+                seq: None,
+                msg_id: None,
+            },
+            origin_sender_id: "server".to_string(),
+        }
+    }
+
+    /// Ответ сервера, адресованный только отправителю этого сообщения.
+    pub fn reply(&self, command: &str, payload: Value) -> ClientMessage {
+        let target = Target {
+            scope: "ids".to_string(),
+            types: Vec::new(),
+            ids: vec![self.origin_sender_id.clone()],
+        };
+        let mut reply = ClientMessage::server(&self.msg.room_id, Some(target), command, payload);
+        reply.msg.msg_id = self.msg.msg_id.clone();
+        reply
+    }
+
+    /// ERROR отправителю этого сообщения с конвертом ошибки в payload.
+    /// Если у сообщения есть msg_id, он же становится correlation_id ошибки.
+    pub fn error(&self, err: ApiError) -> ClientMessage {
+        ClientMessage::error_for(
+            &self.msg.room_id,
+            &self.origin_sender_id,
+            err,
+            self.msg.msg_id.clone(),
+        )
+    }
+
+    /// ERROR участнику, когда исходного сообщения нет или оно не разобрано
+    /// (ошибка разбора, проверки или несовпадение отправителя).
+    pub fn error_for(
+        room_id: &str,
+        sender_id: &str,
+        mut err: ApiError,
+        msg_id: Option<String>,
+    ) -> ClientMessage {
+        if let Some(msg_id) = &msg_id {
+            err.correlation_id = msg_id.clone();
+        }
+        let target = Target {
+            scope: "ids".to_string(),
+            types: Vec::new(),
+            ids: vec![sender_id.to_string()],
+        };
+        let mut reply = ClientMessage::server(room_id, Some(target), "ERROR", err.body());
+        reply.msg.msg_id = msg_id;
+        reply
+    }
+
+    /// ACK отправителю: чем закончилась обработка его сообщения.
+    /// Не рассылается и не попадает в историю — уходит в транспорт отправителя.
+    pub fn ack(&self, outcome: Outcome) -> ClientMessage {
+        let mut payload = serde_json::json!({ "msg_id": self.msg.msg_id });
+        match outcome {
+            Outcome::Accepted => payload["status"] = "accepted".into(),
+            Outcome::Rejected(mut err) => {
+                if let Some(msg_id) = &self.msg.msg_id {
+                    err.correlation_id = msg_id.clone();
+                }
+                payload["status"] = "rejected".into();
+                payload["error"] = serde_json::to_value(err).unwrap_or(Value::Null);
+            }
+            Outcome::Delivered(Delivery { seq, recipients }) => {
+                payload["status"] = "delivered".into();
+                if let Some(seq) = seq {
+                    payload["seq"] = seq.into();
+                }
+                payload["delivered"] = recipients.into();
+            }
+        }
+        self.reply("ACK", payload)
+    }
+}
+
+/// Команда клиента на обработку (WS, /send); ответ — ACK для отправителя
+#[derive(Message)]
+#[rtype(result = "ClientMessage")]
+pub struct Submit(pub ClientMessage);
+
+/// Сообщение разослано: его seq в истории (у прямого ответа его нет)
+/// и сколько подключений его получили
+#[derive(Clone, Copy, Debug)]
+pub struct Delivery {
+    pub seq: Option<u64>,
+    pub recipients: usize,
+}
+
+/// Чем закончилась обработка команды отправителя
+pub enum Outcome {
+    /// Принята, рассылка отложена (окно схлопывания перетаскиваний)
+    Accepted,
+    /// Отклонена; отправителю также уходит ERROR
+    Rejected(ApiError),
+    /// Разослана (для GET_STATE — ответ отправителю, без seq)
+    Delivered(Delivery),
+}
+
+/// Каналы WS‑подписчика: очередь рассылки и закрытие соединения сервером.
+#[derive(Clone)]
+pub struct WsClient {
+    pub outbox: Arc<Outbox<ClientMessage>>,
+    pub close: Recipient<Disconnect>,
+}
+
 /// Регистрация WebSocket‑подписчика.
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct RegisterWs {
+    pub room_id: String,
+    pub sender_id: String,
+    pub role: String,
+    pub session: String,
+    pub conn: u64,
+    /// Сессия продолжена по токену из `?resume=`
+    pub resumed: bool,
+    pub outbox: Arc<Outbox<ClientMessage>>,
+    pub close: Recipient<Disconnect>,
+}
+
+/// WS‑актор остановлен: снять подписчика с регистрации
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UnregisterWs {
+    pub room_id: String,
+    pub conn: u64,
+    /// Почему соединение закрылось — для присутствия
+    pub reason: LeaveReason,
+}
+
+/// HELLO по WS: клиент может продолжить прежнюю сессию по токену
+/// и получить пропущенное с `since`. Отвечает WELCOME, результат — id сессии
+/// подключения (новый, если сессия продолжена).
+#[derive(Message)]
+#[rtype(result = "Result<String, ApiError>")]
+pub struct Hello {
+    pub room_id: String,
     pub sender_id: String,
-    pub rec: Recipient<ClientMessage>,
+    pub role: String,
+    pub session: String,
+    pub conn: u64,
+    pub resume_token: Option<String>,
+    pub since: Option<u64>,
+    pub outbox: Arc<Outbox<ClientMessage>>,
 }
 
 pub struct BroadcastServer {
     pub state: actix_web::web::Data<AppState>,
+    /// HTTP‑клиент для пересылки команд в upstream
+    client: reqwest::Client,
 }
 
 impl BroadcastServer {
     pub fn new(state: actix_web::web::Data<AppState>) -> Self {
-        Self { state }
+        Self {
+            state,
+            client: reqwest::Client::new(),
+        }
     }
 }
 
+/// Повторная доставка пропущенного WS‑клиенту (команда RESUME {since}).
+/// Сообщения уходят только в это соединение.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Resume {
+    pub room_id: String,
+    pub sender_id: String,
+    pub role: String,
+    pub since: u64,
+    pub outbox: Arc<Outbox<ClientMessage>>,
+}
+
+/// Количество WebSocket‑подписчиков комнаты
 #[derive(Message)]
 #[rtype(result = "usize")]
-pub struct GetCount;
+pub struct GetCount {
+    pub room_id: String,
+    /// считать ли скрытые роли (наблюдатель, ADMIN)
+    pub include_hidden: bool,
+}
 
 impl Actor for BroadcastServer {
     type Context = Context<Self>;
@@ -42,36 +260,87 @@ impl Actor for BroadcastServer {
     fn started(&mut self, ctx: &mut Self::Context) {
         let state = self.state.clone();
 
-        ctx.run_interval(Duration::from_secs(PING_INTERVAL), move |_act, _ctx| {
-            let state = state.clone();
+        let compact_state = state.clone();
+        ctx.run_interval(Duration::from_secs(COMPACT_INTERVAL), move |_act, _ctx| {
+            let state = compact_state.clone();
             actix::spawn(async move {
-                // SSE: heartbeat
-                {
-                    let mut sse = state.sse_senders.lock().await;
-                    sse.retain(|(_, tx)| tx.send(String::new()).is_ok());
+                for room in state.rooms.all().await {
+                    room.compact().await;
                 }
+            });
+        });
 
-                // WebSocket: ping‑сообщение для чистки мёртвых
-                {
-                    // Собираем шаблон валидного IncomingMessage:
-                    let ping_msg = IncomingMessage {
-                        // Берём любую непустую строку, допустим, из конфига:
-                        room_id: ROOM_CONFIG.room.clone(),
-                        sender: crate::validator::message::Sender {
-                            id: String::new(),
-                            sender_type: "heartbeat".to_string(),
-                        },
-                        target: None,
-                        msg_command: Some("PING".to_string()),
-                        payload: None,
-                    };
-                    let ping_client = ClientMessage {
-                        msg: ping_msg.clone(),
-                        origin_sender_id: String::new(),
-                    };
-
-                    let mut subs = state.ws_subs.lock().await;
-                    subs.retain(|(_, rec)| rec.try_send(ping_client.clone()).is_ok());
+        // Прикладной PING и чистка ушедших клиентов. WS‑подписчики снимаются
+        // с регистрации сами (ping/pong протокола в MyWs), SSE — когда их поток завершается.
+        let liveness = state.liveness.clone();
+        ctx.run_interval(liveness.app_ping_interval, move |_act, _ctx| {
+            let state = state.clone();
+            let liveness = liveness.clone();
+            actix::spawn(async move {
+                for room in state.rooms.all().await {
+                    // SSE: очередь, закрытую из‑за медленного клиента, снимаем сразу —
+                    // поток такого клиента может не завершиться, пока он не читает
+                    let slow = room
+                        .sse_senders
+                        .write()
+                        .await
+                        .retain(|sub| !sub.tx.stats().closed);
+                    for sub in slow {
+                        room.user_disconnected(&sub.id, Transport::Sse, LeaveReason::SlowConsumer)
+                            .await;
+                    }
+                    // SSE: пустое событие
+                    if liveness.app_ping(Transport::Sse) {
+                        for sub in room.sse_senders.read().await.iter() {
+                            sub.tx.push_if_idle(SseEvent {
+                                seq: None,
+                                data: String::new(),
+                                key: None,
+                            });
+                        }
+                    }
+
+                    // WebSocket: сообщение PING
+                    if liveness.app_ping(Transport::Ws) {
+                        let ping = ClientMessage {
+                            msg: IncomingMessage {
+                                room_id: room.id.clone(),
+                                sender: Sender {
+                                    id: String::new(),
+                                    sender_type: "heartbeat".to_string(),
+                                    sign: None,
+                                },
+                                target: None,
+                                msg_command: Some("PING".to_string()),
+                                payload: None,
+                                seq: None,
+                                msg_id: None,
+                            },
+                            origin_sender_id: String::new(),
+                        };
+                        for sub in room.ws_subs.read().await.iter() {
+                            sub.tx.outbox.push_if_idle(ping.clone());
+                        }
+                    }
+
+                    // LP: сессии без опросов дольше lp_session_timeout удаляются
+                    let lp_timeout = room.config.read().await.lp_session_timeout;
+                    room.lp_senders
+                        .write()
+                        .await
+                        .retain(|sub| sub.tx.idle_for().is_none_or(|idle| idle <= lp_timeout));
+                    let idle = room.presence.lock().await.prune_idle_polls(lp_timeout);
+                    for (id, role) in idle {
+                        room.announce(
+                            "USER_LEFT",
+                            &id,
+                            &role,
+                            Transport::LongPolling,
+                            Some(LeaveReason::Idle),
+                        )
+                        .await;
+                        room.user_gone(&id).await;
+                    }
                 }
             });
         });
@@ -79,88 +348,514 @@ impl Actor for BroadcastServer {
 }
 
 impl Handler<RegisterWs> for BroadcastServer {
+    type Result = ResponseFuture<()>;
+    fn handle(&mut self, msg: RegisterWs, _: &mut Self::Context) -> Self::Result {
+        let state = self.state.clone();
+        Box::pin(async move {
+            let Some(room) = state.rooms.get(&msg.room_id).await else {
+                return;
+            };
+            // Сначала присутствие нового подключения, потом закрытие прежних —
+            // так продолженная сессия не вызывает USER_LEFT/USER_JOINED
+            room.user_connected(&msg.sender_id, &msg.role, Transport::Ws)
+                .await;
+            room.take_over(&msg.sender_id, &msg.session).await;
+            let welcome = room
+                .welcome(&msg.sender_id, &msg.role, &msg.session, msg.resumed)
+                .await;
+            msg.outbox.push_unbounded(welcome);
+            room.ws_subs.write().await.insert(Subscriber {
+                id: msg.sender_id,
+                role: msg.role,
+                session: msg.session,
+                conn: msg.conn,
+                tx: WsClient {
+                    outbox: msg.outbox,
+                    close: msg.close,
+                },
+            });
+        })
+    }
+}
+
+impl Handler<UnregisterWs> for BroadcastServer {
     type Result = ();
-    fn handle(&mut self, msg: RegisterWs, _: &mut Self::Context) {
+    fn handle(&mut self, msg: UnregisterWs, _: &mut Self::Context) {
         let state = self.state.clone();
         actix::spawn(async move {
-            let mut subs = state.ws_subs.lock().await;
-            subs.push((msg.sender_id, msg.rec));
+            if let Some(room) = state.rooms.get(&msg.room_id).await {
+                room.remove_ws(msg.conn, msg.reason).await;
+            }
+        });
+    }
+}
+
+impl Handler<Hello> for BroadcastServer {
+    type Result = ResponseFuture<Result<String, ApiError>>;
+    fn handle(&mut self, msg: Hello, _: &mut Self::Context) -> Self::Result {
+        let state = self.state.clone();
+        Box::pin(async move {
+            let room = state
+                .rooms
+                .get(&msg.room_id)
+                .await
+                .ok_or_else(|| auth::unknown_room(&msg.room_id))?;
+            let session = match &msg.resume_token {
+                Some(token) => {
+                    let session = auth::verify_resume(
+                        &*room.config.read().await,
+                        &msg.room_id,
+                        &msg.sender_id,
+                        &msg.role,
+                        token,
+                    )?;
+                    // Прежние подключения этой сессии закрываются, текущее занимает её
+                    if session != msg.session {
+                        room.close_sessions(
+                            "Сессия продолжена в другом подключении",
+                            LeaveReason::SessionClosed,
+                            |_, _, s| s == session,
+                        )
+                        .await;
+                        if let Some(sub) = room.ws_subs.write().await.get_mut(msg.conn) {
+                            sub.session = session.clone();
+                        }
+                    }
+                    session
+                }
+                None => msg.session.clone(),
+            };
+
+            let welcome = room
+                .welcome(
+                    &msg.sender_id,
+                    &msg.role,
+                    &session,
+                    msg.resume_token.is_some(),
+                )
+                .await;
+            msg.outbox.push_unbounded(welcome);
+            if let Some(since) = msg.since {
+                let history = room.history.lock().await;
+                for missed in history
+                    .since(since)
+                    .filter(|m| m.is_for_user(&msg.sender_id, &msg.role))
+                {
+                    msg.outbox.push_unbounded(missed.clone());
+                }
+            }
+            Ok(session)
+        })
+    }
+}
+
+impl Handler<Resume> for BroadcastServer {
+    type Result = ();
+    fn handle(&mut self, msg: Resume, _: &mut Self::Context) {
+        let state = self.state.clone();
+        actix::spawn(async move {
+            let Some(room) = state.rooms.get(&msg.room_id).await else {
+                return;
+            };
+            let history = room.history.lock().await;
+            for missed in history
+                .since(msg.since)
+                .filter(|m| m.is_for_user(&msg.sender_id, &msg.role))
+            {
+                msg.outbox.push_unbounded(missed.clone());
+            }
         });
     }
 }
 
 impl Handler<GetCount> for BroadcastServer {
     type Result = ResponseFuture<usize>;
-    fn handle(&mut self, _: GetCount, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: GetCount, _: &mut Context<Self>) -> Self::Result {
         let state = self.state.clone();
-        Box::pin(async move { state.ws_subs.lock().await.len() })
+        Box::pin(async move {
+            match state.rooms.get(&msg.room_id).await {
+                Some(room) => room
+                    .ws_subs
+                    .read()
+                    .await
+                    .iter()
+                    .filter(|sub| msg.include_hidden || !permissions::is_hidden(&sub.role))
+                    .count(),
+                None => 0,
+            }
+        })
     }
 }
 
-impl Handler<ClientMessage> for BroadcastServer {
-    type Result = ();
+impl Handler<Submit> for BroadcastServer {
+    type Result = ResponseFuture<ClientMessage>;
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Self::Context) {
-        let text = serde_json::to_string(&msg.msg).unwrap();
+    fn handle(&mut self, Submit(msg): Submit, _: &mut Self::Context) -> Self::Result {
         let state = self.state.clone();
+        let client = self.client.clone();
 
-        println!("> {}", text);
-        actix::spawn(async move {
-            // WS
-            {
-                let mut subs = state.ws_subs.lock().await;
-                let mut keep = Vec::new();
-                for (sid, rec) in subs.drain(..) {
-                    //todo убрать msg.origin_sender_id.clone()
-                    if sid != msg.origin_sender_id {
-                        let _ = rec.do_send(ClientMessage {
-                            msg: msg.msg.clone(),
-                            origin_sender_id: msg.origin_sender_id.clone(),
-                        });
-                    } else {
-                        // свой не получает
+        Box::pin(async move {
+            let ack = msg.clone();
+            let outcome = process(state, client, msg).await;
+            ack.ack(outcome)
+        })
+    }
+}
+
+/// Разбирает команду по реестру и проверяет права роли (и управление
+/// комнатой) — одинаково для всех транспортов. Допуск в комнату
+/// перепроверяется: конфиг мог измениться после подключения.
+pub async fn check(room: &Room, msg: &IncomingMessage) -> Result<Command, ApiError> {
+    let config = room.config.read().await;
+    let control = room.control.lock().await;
+    if !config.role_allowed(&msg.sender.id, &msg.sender.sender_type) {
+        return Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            format!(
+                "Пользователь {} больше не допущен в комнату с ролью {}",
+                msg.sender.id, msg.sender.sender_type
+            ),
+        )
+        .field("sender.type"));
+    }
+    let command = commands::parse(msg)?;
+    commands::authorize(msg, &command, &config, &control)?;
+    Ok(command)
+}
+
+/// Обработка команды отправителя: права, upstream, сцена, рассылка
+async fn process(
+    state: actix_web::web::Data<AppState>,
+    client: reqwest::Client,
+    mut msg: ClientMessage,
+) -> Outcome {
+    // Сообщение уходит только в свою комнату
+    let Some(room) = state.rooms.get(&msg.msg.room_id).await else {
+        println!("Сообщение для неизвестной комнаты {}", msg.msg.room_id);
+        return Outcome::Rejected(auth::unknown_room(&msg.msg.room_id));
+    };
+
+    room.presence.lock().await.touch(&msg.msg.sender.id);
+
+    let mut command = match check(&room, &msg.msg).await {
+        Ok(command) => command,
+        Err(e) => {
+            send_reply(&room, msg.error(e.clone())).await;
+            return Outcome::Rejected(e);
+        }
+    };
+
+    // Команды для upstream: в комнату уходит только его ответ
+    let upstream = {
+        let config = room.config.read().await;
+        match (&msg.msg.msg_command, &config.upstream) {
+            (Some(command), Some(url)) if config.forwards(command) => {
+                Some((url.clone(), config.upstream_timeout))
+            }
+            _ => None,
+        }
+    };
+    if let Some((url, timeout)) = upstream {
+        match upstream::forward(&client, &url, timeout, &msg.msg).await {
+            Ok(reply) => {
+                let name = reply
+                    .command
+                    .or_else(|| msg.msg.msg_command.clone())
+                    .unwrap_or_default();
+                let mut answer = ClientMessage::server(
+                    &room.id,
+                    msg.msg.target.clone(),
+                    &name,
+                    reply.payload.unwrap_or(Value::Null),
+                );
+                // ответ upstream несёт id исходного запроса
+                answer.msg.msg_id = msg.msg.msg_id.clone();
+                // команды сцены из ответа применяются к сцене
+                command = match commands::parse(&answer.msg) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        send_reply(&room, msg.error(e.clone())).await;
+                        return Outcome::Rejected(e);
                     }
-                    // в любом случае WS‑клиент остаётся подписанным
-                    keep.push((sid, rec));
-                }
-                *subs = keep;
+                };
+                msg = answer;
+            }
+            Err(e) => {
+                println!("Upstream {}: {}", url, e);
+                send_reply(&room, msg.error(e.clone())).await;
+                return Outcome::Rejected(e);
             }
+        }
+    }
 
-            // SSE
-            {
-                let mut sse = state.sse_senders.lock().await;
-                let mut keep = Vec::new();
-                for (sid, tx) in sse.drain(..) {
-                    if sid != msg.origin_sender_id {
-                        let _ = tx.send(text.clone());
-                    }
-                    keep.push((sid, tx));
+    match command {
+        Command::Scene(cmd) => match apply_scene(&room, msg, cmd).await {
+            ControlFlow::Continue(msg) => relay(room, msg).await,
+            ControlFlow::Break(outcome) => outcome,
+        },
+        Command::Control(cmd) => apply_control(room, msg, cmd).await,
+        _ => relay(room, msg).await,
+    }
+}
+
+/// Применяет команду управления комнатой. Вместо самой команды всем
+/// рассылается ROOM_CONTROL с новым состоянием; KICK затем закрывает
+/// подключения пользователя.
+async fn apply_control(room: Arc<Room>, msg: ClientMessage, cmd: ControlCommand) -> Outcome {
+    let by = msg.msg.sender.id.clone();
+    let target_roles = match cmd.user() {
+        Some(id) => room.presence.lock().await.roles(id),
+        None => Vec::new(),
+    };
+    let result = {
+        let config = room.config.read().await;
+        let mut control = room.control.lock().await;
+        control
+            .apply(&cmd, &by, &target_roles, &config)
+            .map(|_| control.clone())
+    };
+    let control = match result {
+        Ok(control) => control,
+        Err(e) => {
+            send_reply(&room, msg.error(e.clone())).await;
+            return Outcome::Rejected(e);
+        }
+    };
+    println!("Комната {}: {} {:?}", room.id, by, cmd);
+    let mut event = room.control_event(&cmd, &by, &control);
+    event.msg.msg_id = msg.msg.msg_id.clone();
+    let outcome = relay(room.clone(), event).await;
+    if let ControlCommand::Kick { id } = &cmd {
+        room.kick(id).await;
+    }
+    outcome
+}
+
+/// Рассылка команды отправителя. При включённом окне схлопывания
+/// перетаскивание откладывается до конца окна (рассылается последнее
+/// положение объекта), а любая другая команда сначала сбрасывает
+/// отложенные перетаскивания — порядок команд не нарушается.
+async fn relay(room: Arc<Room>, msg: ClientMessage) -> Outcome {
+    let window = room.config.read().await.coalesce_window;
+    let key = window.and_then(|_| {
+        scene::drag_key(msg.msg.msg_command.as_deref(), msg.msg.payload.as_ref())
+            .map(|(command, id)| (command.to_string(), id.to_string()))
+    });
+
+    let mut drag = room.drag.lock().await;
+    match (window, key) {
+        (Some(window), Some(key)) => {
+            let Some(opened) = drag.hold(key.clone(), msg) else {
+                return Outcome::Accepted;
+            };
+            drop(drag);
+            actix::spawn(async move {
+                tokio::time::sleep(window).await;
+                let mut drag = room.drag.lock().await;
+                if let Some(msg) = drag.take(&key, opened) {
+                    deliver(&room, msg).await;
                 }
-                // for (sid, tx) in sse.drain(..) {
-                //     if sid.clone() != msg.origin_sender_id.clone() {
-                //         let _ = tx.send(text.clone());
-                //     }
-                //     keep.push((sid, tx));
-                // }
-                *sse = keep;
+            });
+            Outcome::Accepted
+        }
+        _ => {
+            for held in drag.drain() {
+                deliver(&room, held).await;
             }
+            Outcome::Delivered(deliver(&room, msg).await)
+        }
+    }
+}
 
-            // Long‑Polling — рассылка чужим LP и удаление их
-            {
-                let mut lps = state.lp_senders.lock().await;
-                let mut keep = Vec::new();
-                for (sid, tx) in lps.drain(..) {
-                    if sid != msg.origin_sender_id.clone() {
-                        // чужим — отправляем
-                        let _ = tx.send(text.clone());
-                        // и не сохраняем, т.к. одноразовый канал
-                    } else {
-                        // своему — не шлём, но сохраняем, чтобы он мог ждать дальше
-                        keep.push((sid, tx));
-                    }
-                }
-                *lps = keep;
+/// Применяет команду сцены к состоянию комнаты.
+/// `Continue` — сообщение для рассылки, `Break` — рассылать нечего
+/// (ответ или ошибка уже ушли отправителю).
+async fn apply_scene(
+    room: &Room,
+    mut msg: ClientMessage,
+    cmd: SceneCommand,
+) -> ControlFlow<Outcome, ClientMessage> {
+    // команда, которая сцену только читает (GET_STATE), получает ответ сама
+    let query = msg
+        .msg
+        .msg_command
+        .as_deref()
+        .and_then(commands::find)
+        .is_some_and(|spec| !spec.mutates);
+    let result = room.scene.lock().await.apply(cmd);
+    match result {
+        Err(e) => {
+            send_reply(room, msg.error(e.clone())).await;
+            return ControlFlow::Break(Outcome::Rejected(e));
+        }
+        Ok(Some(scene)) if query => {
+            let delivery = send_reply(room, msg.reply("STATE", scene)).await;
+            return ControlFlow::Break(Outcome::Delivered(delivery));
+        }
+        Ok(Some(payload)) => msg.msg.payload = Some(payload),
+        Ok(None) => {}
+    }
+    ControlFlow::Continue(msg)
+}
+
+/// Рассылка сообщения адресатам комнаты по всем транспортам.
+/// Сообщение получает seq и попадает в историю комнаты; история заблокирована
+/// на всё время рассылки, поэтому порядок seq совпадает с порядком доставки,
+/// а подключающийся в этот момент клиент не пропустит и не получит дважды.
+pub async fn deliver(room: &Room, mut msg: ClientMessage) -> Delivery {
+    let mut history = room.history.lock().await;
+    let seq = history.record(&mut msg);
+    room.writer.append(&room.id, msg.clone());
+    let recipients = fan_out(room, &msg).await;
+    drop(history);
+    Delivery {
+        seq: Some(seq),
+        recipients,
+    }
+}
+
+/// Прямой ответ отправителю (ERROR, STATE): доставляется адресатам так же,
+/// но без seq — в историю и журнал не попадает и при переподключении не повторяется.
+async fn send_reply(room: &Room, msg: ClientMessage) -> Delivery {
+    Delivery {
+        seq: None,
+        recipients: fan_out(room, &msg).await,
+    }
+}
+
+/// Доставка сообщения адресатам по всем транспортам
+async fn fan_out(room: &Room, msg: &ClientMessage) -> usize {
+    let mut recipients = 0;
+    let event = SseEvent::from_message(msg);
+
+    // Очереди подписчиков ограничены: при переполнении — политика комнаты
+    let policy = room.config.read().await.slow_consumer;
+
+    // WS — адресатам шлём, подписанными остаются все.
+    // Для ids/type адресаты берутся из индексов, а не перебором всех.
+    {
+        let subs = room.ws_subs.read().await;
+        for sub in subs.recipients(msg) {
+            recipients += 1;
+            if sub.tx.outbox.push(msg.clone(), policy) == Pushed::Overflow {
+                println!("WebSocket {}: {}", sub.id, SLOW_CONSUMER);
+                // отключение снимет подписчика с регистрации (UnregisterWs)
+                // очередь не разбирается — недоставленное выбрасываем
+                sub.tx
+                    .outbox
+                    .abort(Some(room.session_closed(SLOW_CONSUMER)));
+                sub.tx.close.do_send(Disconnect {
+                    reason: SLOW_CONSUMER.to_string(),
+                    leave: LeaveReason::SlowConsumer,
+                });
             }
-        });
+        }
+    }
+
+    // SSE
+    {
+        let sse = room.sse_senders.read().await;
+        for sub in sse.recipients(msg) {
+            recipients += 1;
+            if sub.tx.push(event.clone(), policy) == Pushed::Overflow {
+                println!("SSE {}: {}", sub.id, SLOW_CONSUMER);
+                // поток завершится, и SseGuard снимет подписчика с регистрации
+                let closed = room.session_closed(SLOW_CONSUMER);
+                sub.tx.abort(Some(SseEvent::from_message(&closed)));
+            }
+        }
+    }
+
+    // Long‑Polling — в очереди сессий адресатов, заберут при опросе
+    {
+        let lps = room.lp_senders.read().await;
+        for sub in lps.recipients(msg).filter(|sub| !sub.tx.is_closed()) {
+            recipients += 1;
+            sub.tx.push(&msg.msg);
+        }
+    }
+
+    recipients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoomConfig;
+    use crate::upstream::mock::MockUpstream;
+    use actix_web::http::StatusCode;
+
+    /// Сервер с одной комнатой, пересылающей CHECK_ANSWER в `upstream`
+    async fn state(upstream: &str, name: &str) -> actix_web::web::Data<AppState> {
+        let state = AppState::for_tests(&format!("upstream-{}", name));
+        let config = RoomConfig::parse(&format!(
+            "room = room_1\n\
+             teacher = user_1\n\
+             authorised_students = user_2\n\
+             sign_key = secret\n\
+             upstream = {}\n\
+             upstream_commands = CHECK_ANSWER\n\
+             upstream_timeout_ms = 200\n",
+            upstream
+        ))
+        .unwrap();
+        state.rooms.upsert(config).await;
+        actix_web::web::Data::new(state)
+    }
+
+    fn check_answer() -> ClientMessage {
+        let msg: IncomingMessage = serde_json::from_value(serde_json::json!({
+            "room_id": "room_1",
+            "sender": {"id": "user_1", "type": "учитель"},
+            "command": "CHECK_ANSWER",
+            "payload": {"answer": 42},
+            "msg_id": "1",
+        }))
+        .unwrap();
+        ClientMessage {
+            origin_sender_id: msg.sender.id.clone(),
+            msg,
+        }
+    }
+
+    async fn rejected(mock: MockUpstream, name: &str) -> ErrorCode {
+        let state = state(&mock.start(), name).await;
+        match process(state, reqwest::Client::new(), check_answer()).await {
+            Outcome::Rejected(e) => e.code,
+            _ => panic!("команда должна быть отклонена"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn upstream_reply_is_delivered_to_the_room() {
+        let state = state(&MockUpstream::default().start(), "ok").await;
+        let outcome = process(state.clone(), reqwest::Client::new(), check_answer()).await;
+        let Outcome::Delivered(delivery) = outcome else {
+            panic!("ответ upstream должен быть разослан");
+        };
+        let room = state.rooms.get("room_1").await.unwrap();
+        let history = room.history.lock().await;
+        let answer = history.since(0).last().unwrap();
+        assert_eq!(answer.msg.seq, delivery.seq);
+        assert_eq!(answer.msg.msg_id.as_deref(), Some("1"));
+        assert_eq!(answer.msg.payload.as_ref().unwrap()["echo"]["answer"], 42);
+    }
+
+    #[actix_web::test]
+    async fn upstream_error_is_reported() {
+        let mock = MockUpstream {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            ..Default::default()
+        };
+        assert_eq!(rejected(mock, "error").await, ErrorCode::UpstreamError);
+    }
+
+    #[actix_web::test]
+    async fn slow_upstream_times_out() {
+        let mock = MockUpstream {
+            delay: Duration::from_secs(5),
+            ..Default::default()
+        };
+        assert_eq!(rejected(mock, "timeout").await, ErrorCode::UpstreamTimeout);
     }
 }
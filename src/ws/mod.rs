@@ -2,45 +2,230 @@ pub mod broadcast;
 pub mod route;
 
 use crate::{
-    validator::message::{IncomingMessage, Validate},
+    codec::{Encoding, Frame},
+    commands::{self, Command},
+    config::server::Liveness,
+    errors::{ApiError, ErrorCode},
+    outbox::Outbox,
+    presence::LeaveReason,
+    validator::message::{IncomingMessage, Sender, Validate},
     ClientMessage,
 };
 use actix::prelude::*;
 use actix::Addr;
 use actix_web_actors::ws as actix_ws;
-use broadcast::{BroadcastServer, RegisterWs};
-use std::time::Duration;
+use broadcast::{BroadcastServer, Hello, RegisterWs, Resume, Submit, UnregisterWs};
+use serde::Serialize;
+use std::sync::Arc;
 use std::time::Instant;
 
+/// Закрытие соединения по инициативе сервера (например, комната закрыта).
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub reason: String,
+    /// Причина для присутствия (USER_LEFT)
+    pub leave: LeaveReason,
+}
+
 // --- WebSocket актор ---
 pub struct MyWs {
     addr: Addr<BroadcastServer>,
-    hb: Instant, // отслеживаем последнее "pong"
+    hb: Instant, // когда клиент последний раз что-то присылал (pong или любой кадр)
+    /// комната, id и роль, подтверждённые подписью при подключении
+    room: String,
     sender_id: String,
+    role: String,
+    /// Сессия, выданная сервером (или продолженная по токену)
+    session: String,
+    conn: u64,
+    resumed: bool,
+    /// Очередь рассылки этому подключению (ограничена subscriber_queue)
+    outbox: Arc<Outbox<ClientMessage>>,
+    /// Кодировка двоичных кадров (?encoding= или подпротокол)
+    encoding: Encoding,
+    /// Интервал ping и допустимое молчание клиента из конфига сервера
+    liveness: Liveness,
+    /// Почему соединение закрывается — уходит в присутствие при снятии с регистрации
+    leave: LeaveReason,
+    /// Сервер закрывает соединение с этой причиной, как только очередь опустеет
+    closing: Option<String>,
+    /// Очередь закрыта и разобрана до конца
+    drained: bool,
 }
 
 impl MyWs {
+    /// Ping протокола каждые ws_ping_interval; клиент, молчащий дольше
+    /// ws_pong_timeout, отключается (и снимается с регистрации в stopped)
     fn start_heartbeat(&self, ctx: &mut actix_ws::WebsocketContext<Self>) {
-        ctx.run_interval(Duration::from_secs(300), |act, ctx| {
-            //таймаут
-            if Instant::now().duration_since(act.hb) > Duration::from_secs(30) {
+        ctx.run_interval(self.liveness.ws_ping_interval, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.liveness.ws_pong_timeout {
                 println!("WebSocket {} таймаут, закрытие", act.sender_id);
-                ctx.stop();
+                act.leave = LeaveReason::Timeout;
+                act.close_with(ctx, "Нет ответа на ping".to_string());
                 return;
             }
             ctx.ping(b"");
         });
     }
+
+    /// Отправляет значение клиенту в кодировке подключения
+    fn send_value<T: Serialize>(&self, ctx: &mut actix_ws::WebsocketContext<Self>, value: &T) {
+        match self.encoding.encode(value) {
+            Ok(Frame::Text(text)) => ctx.text(text),
+            Ok(Frame::Binary(data)) => ctx.binary(data),
+            Err(e) => eprintln!("Ошибка сериализации для {}: {}", self.sender_id, e),
+        }
+    }
+
+    /// Закрывает соединение с причиной
+    fn close_with(&self, ctx: &mut actix_ws::WebsocketContext<Self>, reason: String) {
+        ctx.close(Some(actix_ws::CloseReason {
+            code: actix_ws::CloseCode::Away,
+            description: Some(reason),
+        }));
+        ctx.stop();
+    }
+
+    /// ERROR этому подключению в том же конверте, что и у BroadcastServer
+    fn send_error(
+        &self,
+        ctx: &mut actix_ws::WebsocketContext<Self>,
+        error: ApiError,
+        msg_id: Option<String>,
+    ) {
+        let reply = ClientMessage::error_for(&self.room, &self.sender_id, error, msg_id);
+        self.send_value(ctx, &reply.msg);
+    }
+
+    /// Разобранное сообщение клиента (из текстового или двоичного кадра)
+    fn on_message(
+        &mut self,
+        mut parsed: IncomingMessage,
+        ctx: &mut actix_ws::WebsocketContext<Self>,
+    ) {
+        // Комнату и отправителя можно не указывать: их задаёт само подключение
+        if parsed.room_id.is_empty() {
+            parsed.room_id = self.room.clone();
+        }
+        if parsed.sender.id.is_empty() && parsed.sender.sender_type.is_empty() {
+            parsed.sender = Sender {
+                id: self.sender_id.clone(),
+                sender_type: self.role.clone(),
+                sign: None,
+            };
+        }
+        // Валидируем
+        if let Err(err) = parsed.validate() {
+            // Можно отправить клиенту ошибку или просто пропустить
+            self.send_error(ctx, err, parsed.msg_id);
+            return;
+        }
+        // Соединение уже подписано — сообщение должно быть от его владельца
+        if parsed.room_id != self.room
+            || parsed.sender.id != self.sender_id
+            || parsed.sender.sender_type != self.role
+        {
+            self.send_error(
+                ctx,
+                ApiError::new(
+                    ErrorCode::SenderMismatch,
+                    "Отправитель не совпадает с подключением",
+                )
+                .field("sender"),
+                parsed.msg_id,
+            );
+            return;
+        }
+        // HELLO и RESUME исполняет само подключение; остальные команды
+        // (и ошибки их разбора) — BroadcastServer, одинаково для всех транспортов
+        match commands::parse(&parsed) {
+            // HELLO {resume_token?, since?} — продолжение прежней сессии, ответ WELCOME
+            Ok(Command::Hello(hello)) => {
+                let msg_id = parsed.msg_id.clone();
+                self.addr
+                    .send(Hello {
+                        room_id: self.room.clone(),
+                        sender_id: self.sender_id.clone(),
+                        role: self.role.clone(),
+                        session: self.session.clone(),
+                        conn: self.conn,
+                        resume_token: hello.resume_token,
+                        since: hello.since,
+                        outbox: self.outbox.clone(),
+                    })
+                    .into_actor(self)
+                    .map(|res, act, ctx| match res {
+                        Ok(Ok(session)) => act.session = session,
+                        Ok(Err(e)) => act.send_error(ctx, e, msg_id),
+                        Err(e) => eprintln!("HELLO не обработан: {}", e),
+                    })
+                    .wait(ctx);
+                return;
+            }
+            // RESUME {since} — повторная доставка пропущенного только в это соединение
+            Ok(Command::Resume(resume)) => {
+                self.addr.do_send(Resume {
+                    room_id: self.room.clone(),
+                    sender_id: self.sender_id.clone(),
+                    role: self.role.clone(),
+                    since: resume.since,
+                    outbox: self.outbox.clone(),
+                });
+                return;
+            }
+            _ => {}
+        }
+        // Всё ок – рассылаем дальше:
+        let submit = Submit(ClientMessage {
+            origin_sender_id: parsed.sender.id.clone(),
+            msg: parsed,
+        });
+        if submit.0.msg.msg_id.is_none() {
+            self.addr.do_send(submit);
+            return;
+        }
+        // С msg_id отправитель получает ACK в это же соединение
+        self.addr
+            .send(submit)
+            .into_actor(self)
+            .map(|res, act, ctx| match res {
+                Ok(ack) => act.send_value(ctx, &ack.msg),
+                Err(e) => eprintln!("ACK для {} не получен: {}", act.sender_id, e),
+            })
+            .spawn(ctx);
+    }
 }
 
 impl Actor for MyWs {
     type Context = actix_ws::WebsocketContext<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
         self.start_heartbeat(ctx);
-        let rec = ctx.address().recipient();
-        self.addr.do_send(RegisterWs {
-            sender_id: self.sender_id.clone(),
-            rec,
+        ctx.add_stream(self.outbox.clone().stream());
+        let close = ctx.address().recipient();
+        // Пока регистрация не закончена, сообщения клиента не обрабатываются
+        self.addr
+            .send(RegisterWs {
+                room_id: self.room.clone(),
+                sender_id: self.sender_id.clone(),
+                role: self.role.clone(),
+                session: self.session.clone(),
+                conn: self.conn,
+                resumed: self.resumed,
+                outbox: self.outbox.clone(),
+                close,
+            })
+            .into_actor(self)
+            .map(|_, _, _| ())
+            .wait(ctx);
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.outbox.close(None);
+        self.addr.do_send(UnregisterWs {
+            room_id: self.room.clone(),
+            conn: self.conn,
+            reason: self.leave,
         });
     }
 }
@@ -51,46 +236,30 @@ impl StreamHandler<Result<actix_ws::Message, actix_ws::ProtocolError>> for MyWs
         msg: Result<actix_ws::Message, actix_ws::ProtocolError>,
         ctx: &mut Self::Context,
     ) {
+        // любой кадр от клиента — признак, что он жив
+        self.hb = Instant::now();
         match msg {
             Ok(actix_ws::Message::Text(text)) => {
-                // Пытаемся распарсить в IncomingMessage
+                // Текстовый кадр — всегда JSON
                 match serde_json::from_str::<IncomingMessage>(&text) {
-                    Ok(parsed) => {
-                        // Валидируем
-                        if let Err(err) = parsed.validate() {
-                            // Можно отправить клиенту ошибку или просто пропустить
-                            let _e = ctx.text(
-                                serde_json::to_string(&serde_json::json!({
-                                    "error": format!("Invalid message: {}", err)
-                                }))
-                                .unwrap(),
-                            );
-                            return;
-                        }
-                        // Всё ок – рассылаем дальше:
-                        self.addr.do_send(ClientMessage {
-                            msg: parsed.clone(),
-                            origin_sender_id: parsed.sender.id.clone(),
-                        });
-                    }
-                    Err(e) => {
-                        // JSON некорректен (не тот формат)
-                        let _ = ctx.text(
-                            serde_json::to_string(&serde_json::json!({
-                                "error": format!("JSON parse error {e}")
-                            }))
-                            .unwrap(),
-                        );
-                    }
+                    Ok(parsed) => self.on_message(parsed, ctx),
+                    Err(e) => self.send_error(
+                        ctx,
+                        ApiError::new(ErrorCode::MalformedMessage, format!("JSON parse error {e}")),
+                        None,
+                    ),
+                }
+            }
+            Ok(actix_ws::Message::Binary(data)) => {
+                // Двоичный кадр — в кодировке, согласованной при подключении
+                match self.encoding.decode(&data) {
+                    Ok(parsed) => self.on_message(parsed, ctx),
+                    Err(e) => self.send_error(ctx, e, None),
                 }
             }
             Ok(actix_ws::Message::Ping(msg)) => {
-                self.hb = Instant::now(); // обновляем время последнего ответа
                 ctx.pong(&msg);
             }
-            Ok(actix_ws::Message::Pong(_)) => {
-                self.hb = Instant::now(); // обновляем pong
-            }
             Ok(actix_ws::Message::Close(reason)) => {
                 println!("WebSocket закрыт: {:?}", reason);
                 ctx.stop();
@@ -100,19 +269,136 @@ impl StreamHandler<Result<actix_ws::Message, actix_ws::ProtocolError>> for MyWs
     }
 }
 
-impl Handler<ClientMessage> for MyWs {
-    type Result = ();
+/// Очередь рассылки: сообщения уходят по порядку, а когда сервер закрыл
+/// очередь и она опустела — закрывается и соединение (см. `Disconnect`)
+impl StreamHandler<ClientMessage> for MyWs {
     fn handle(&mut self, msg: ClientMessage, ctx: &mut Self::Context) {
-        // Сериализуем структуру в JSON-строку
-        let text = match serde_json::to_string(&msg.msg) {
-            Ok(json) => json,
-            Err(e) => {
-                eprintln!("Ошибка сериализации IncomingMessage: {}", e);
-                return;
-            }
+        self.send_value(ctx, &msg.msg);
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        self.drained = true;
+        if let Some(reason) = self.closing.take() {
+            self.close_with(ctx, reason);
+        }
+    }
+}
+
+/// Закрытие сервером: очередь к этому моменту уже закрыта, соединение
+/// закрывается, когда клиент получит всё из неё (последним — причину)
+impl Handler<Disconnect> for MyWs {
+    type Result = ();
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        println!(
+            "WebSocket {} закрыт сервером: {}",
+            self.sender_id, msg.reason
+        );
+        self.leave = msg.leave;
+        self.outbox.close(None);
+        if self.drained {
+            self.close_with(ctx, msg.reason);
+        } else {
+            self.closing = Some(msg.reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoomConfig;
+    use crate::session;
+    use crate::AppState;
+    use actix_web::error::PayloadError;
+    use actix_web::web::{Bytes, Data};
+    use futures_util::stream;
+    use std::time::Duration;
+
+    /// Комната room_1 и WS‑подключение учителя, который ничего не присылает
+    /// (поток кадров клиента не заканчивается и пуст). Актор работает, пока
+    /// читается тело ответа — его читает фоновая задача.
+    async fn silent_client(name: &str) -> (Data<AppState>, Addr<MyWs>) {
+        let mut state = AppState::for_tests(&format!("liveness-{}", name));
+        state.liveness.ws_ping_interval = Duration::from_millis(50);
+        state.liveness.ws_pong_timeout = Duration::from_millis(150);
+        let config = RoomConfig::parse(
+            "room = room_1\n\
+             teacher = user_1\n\
+             authorised_students = user_2\n\
+             sign_key = secret\n",
+        )
+        .unwrap();
+        state.rooms.upsert(config).await;
+        let state = Data::new(state);
+        let ws = MyWs {
+            addr: BroadcastServer::new(state.clone()).start(),
+            hb: Instant::now(),
+            room: "room_1".to_string(),
+            sender_id: "user_1".to_string(),
+            role: "учитель".to_string(),
+            session: session::new_session_id(),
+            conn: session::next_connection(),
+            resumed: false,
+            outbox: Arc::new(Outbox::new(16)),
+            encoding: Encoding::Json,
+            liveness: state.liveness.clone(),
+            leave: LeaveReason::Closed,
+            closing: None,
+            drained: false,
         };
+        let req = actix_web::test::TestRequest::get()
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_http_request();
+        let frames = stream::pending::<Result<Bytes, PayloadError>>();
+        let (addr, resp) = actix_ws::WsResponseBuilder::new(ws, &req, frames)
+            .start_with_addr()
+            .unwrap();
+        actix_web::rt::spawn(actix_web::body::to_bytes(resp.into_body()));
+        (state, addr)
+    }
 
-        // Отправляем клиенту эту строку
-        ctx.text(text);
+    /// Причина из USER_LEFT учителя, когда оно появится в истории комнаты
+    async fn leave_reason(state: &AppState) -> Option<String> {
+        let room = state.rooms.get("room_1").await.unwrap();
+        for _ in 0..100 {
+            let reason = room
+                .history
+                .lock()
+                .await
+                .since(0)
+                .find(|m| m.msg.msg_command.as_deref() == Some("USER_LEFT"))
+                .and_then(|m| {
+                    m.msg.payload.as_ref()?["reason"]
+                        .as_str()
+                        .map(str::to_string)
+                });
+            if reason.is_some() {
+                return reason;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        None
+    }
+
+    #[actix_web::test]
+    async fn silent_client_times_out() {
+        let (state, addr) = silent_client("timeout").await;
+        assert_eq!(leave_reason(&state).await.as_deref(), Some("timeout"));
+        assert!(!addr.connected());
+        let room = state.rooms.get("room_1").await.unwrap();
+        assert_eq!(room.ws_subs.read().await.iter().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn server_close_reports_its_reason() {
+        let (state, addr) = silent_client("kicked").await;
+        addr.do_send(Disconnect {
+            reason: "Учитель удалил вас из комнаты".to_string(),
+            leave: LeaveReason::Kicked,
+        });
+        assert_eq!(leave_reason(&state).await.as_deref(), Some("kicked"));
     }
 }
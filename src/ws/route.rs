@@ -1,10 +1,18 @@
 use super::MyWs;
+use crate::auth;
+use crate::codec::{self, Encoding};
+use crate::cors;
+use crate::outbox::Outbox;
+use crate::presence::LeaveReason;
+use crate::session;
 use crate::ws::broadcast::BroadcastServer;
+use crate::AppState;
 use actix::Addr;
 use actix_web::web::Payload;
 use actix_web::{Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws as actix_ws;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Instant;
 
 /// WebSocket маршрут
@@ -12,19 +20,52 @@ pub async fn ws_route(
     req: HttpRequest,
     stream: Payload,
     srv: actix_web::web::Data<Addr<BroadcastServer>>,
+    state: actix_web::web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
+    // 0) Браузер может открыть WS с любого сайта: пускаем только разрешённые origin
+    cors::check_origin(&req, &state.origins)?;
+
     // 1) Парсим query string в HashMap
     let query: HashMap<String, String> = form_urlencoded::parse(req.query_string().as_bytes())
         .into_owned()
         .collect();
 
-    // 2) Достаём id, по умолчанию пустая строка
-    let sender_id = query.get("id").cloned().unwrap_or_default();
+    // 2) Комната, id и роль — из токена доступа (?token= или Authorization: Bearer)
+    // или из room, id, type и sign в query; проверяются до открытия соединения
+    let (room_cfg, sender_id, role) = auth::identify(&req, &query, &state.rooms).await?;
+    let room = room_cfg.id.clone();
+    let config = room_cfg.config.read().await;
+
+    // Сессию выдаёт сервер; с ?resume=<token> подключение занимает прежнюю
+    let (session, resumed) = match query.get("resume") {
+        Some(token) => (
+            auth::verify_resume(&config, &room, &sender_id, &role, token)?,
+            true,
+        ),
+        None => (session::new_session_id(), false),
+    };
+    let capacity = config.subscriber_queue;
+    // Кодировка двоичных кадров: ?encoding=msgpack или подпротокол msgpack / cbor
+    let encoding = Encoding::from_request(&req, query.get("encoding").map(String::as_str))?;
+    drop(config);
 
     let ws = MyWs {
         addr: srv.get_ref().clone(),
         hb: Instant::now(),
+        room,
         sender_id,
+        role,
+        session,
+        conn: session::next_connection(),
+        resumed,
+        outbox: Arc::new(Outbox::new(capacity)),
+        encoding,
+        liveness: state.liveness.clone(),
+        leave: LeaveReason::Closed,
+        closing: None,
+        drained: false,
     };
-    actix_ws::start(ws, &req, stream)
+    actix_ws::WsResponseBuilder::new(ws, &req, stream)
+        .protocols(codec::PROTOCOLS)
+        .start()
 }